@@ -0,0 +1,210 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::StreamExt as _;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, Layer},
+    layer::SubscriberExt as _,
+    util::SubscriberInitExt as _,
+    Layer as _,
+};
+
+// axum-sse的参考客户端
+// 1. 连接/sse 逐行解析事件并打印
+// 2. 断开后按服务端retry指定的间隔重连
+// 3. 重连时通过Last-Event-ID告知服务端最后收到的事件 服务端从历史中补发断开期间的消息
+//    断开太久超出服务端保留的历史(SSE_HISTORY_SIZE)或服务端重启后 无法补发的消息会丢失
+//
+// 运行
+// cargo run --example axum-sse
+// cargo run --example axum-sse-client
+// 重启axum-sse后客户端会自动重连
+
+/// 默认连接的地址 可以通过SSE_URL修改
+const DEFAULT_SSE_URL: &str = "http://127.0.0.1:3000/sse";
+/// 服务端没有发送retry时的重连间隔
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+/// 重连时携带的Header
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+
+/// 一个完整的SSE事件
+#[derive(Debug, Default)]
+struct SseEvent {
+    event: Option<String>,
+    data: Vec<String>,
+    id: Option<String>,
+}
+
+/// 按行解析SSE 空行表示一个事件结束
+#[derive(Debug, Default)]
+struct SseParser {
+    current: SseEvent,
+    // 服务端要求的重连间隔 只有retry的帧也需要记录
+    retry: Option<Duration>,
+}
+
+impl SseParser {
+    /// 处理一行 遇到空行时返回完整的事件 没有data的事件直接丢弃
+    fn feed(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            let event = std::mem::take(&mut self.current);
+            return (!event.data.is_empty()).then_some(event);
+        }
+
+        // 以:开头的是注释 通常是服务端的心跳
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.current.event = Some(value.to_string()),
+            "data" => self.current.data.push(value.to_string()),
+            // 包含空字符的id按规范忽略
+            "id" if !value.contains('\0') => self.current.id = Some(value.to_string()),
+            "retry" => {
+                if let Ok(ms) = value.parse() {
+                    self.retry = Some(Duration::from_millis(ms));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let console_layer = Layer::new()
+        .with_span_events(FmtSpan::CLOSE)
+        .pretty()
+        .with_filter(LevelFilter::INFO);
+
+    tracing_subscriber::registry().with(console_layer).init();
+
+    let url = std::env::var("SSE_URL").unwrap_or_else(|_| DEFAULT_SSE_URL.to_string());
+
+    tokio::select! {
+        _ = run(&url) => {},
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Shutting down");
+        }
+    }
+
+    Ok(())
+}
+
+/// 一直保持订阅 每次断开后等待重连间隔再重新连接
+async fn run(url: &str) {
+    let client = reqwest::Client::new();
+    let mut parser = SseParser::default();
+    let mut last_event_id: Option<String> = None;
+
+    loop {
+        if let Err(err) = subscribe(&client, url, &mut parser, &mut last_event_id).await {
+            tracing::warn!("Subscribe Error: {}", err);
+        }
+
+        let retry = parser.retry.unwrap_or(DEFAULT_RETRY);
+        tracing::info!(
+            "Disconnected, reconnecting in {:?} (last event id: {:?})",
+            retry,
+            last_event_id
+        );
+        tokio::time::sleep(retry).await;
+    }
+}
+
+/// 订阅一次 直到连接断开
+async fn subscribe(
+    client: &reqwest::Client,
+    url: &str,
+    parser: &mut SseParser,
+    last_event_id: &mut Option<String>,
+) -> Result<()> {
+    let mut request = client.get(url).header("Accept", "text/event-stream");
+    if let Some(id) = last_event_id {
+        request = request.header(LAST_EVENT_ID_HEADER, id.as_str());
+    }
+
+    let response = request.send().await?.error_for_status()?;
+    tracing::info!("Connected to {}", url);
+
+    // 上一次连接中断时残留的半个事件不再有效
+    parser.current = SseEvent::default();
+    let mut buffer = Vec::new();
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+        // 处理已经完整的行
+        while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            let Some(event) = parser.feed(line) else {
+                continue;
+            };
+            if let Some(id) = &event.id {
+                *last_event_id = Some(id.clone());
+            }
+            println!(
+                "[{}] {}",
+                event.event.as_deref().unwrap_or("message"),
+                event.data.join("\n")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(parser: &mut SseParser, lines: &[&str]) -> Vec<SseEvent> {
+        lines.iter().filter_map(|line| parser.feed(line)).collect()
+    }
+
+    #[test]
+    fn parser_builds_events_from_lines() {
+        let mut parser = SseParser::default();
+        let events = feed_all(
+            &mut parser,
+            &[
+                ": keep-alive",
+                "retry: 1500",
+                "",
+                "id: abc-1",
+                "event: chat",
+                "data: hello",
+                "data:world",
+                "",
+                "id: bad\0id",
+                "data: no id",
+                "",
+            ],
+        );
+
+        // 只有retry和注释的帧不产生事件
+        assert_eq!(parser.retry, Some(Duration::from_millis(1500)));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].id.as_deref(), Some("abc-1"));
+        assert_eq!(events[0].event.as_deref(), Some("chat"));
+        assert_eq!(events[0].data, ["hello", "world"]);
+        // 包含空字符的id被忽略
+        assert_eq!(events[1].id, None);
+        assert_eq!(events[1].data, ["no id"]);
+    }
+
+    #[test]
+    fn parser_ignores_invalid_retry() {
+        let mut parser = SseParser::default();
+        assert!(feed_all(&mut parser, &["retry: soon", "event: ping", ""]).is_empty());
+        assert_eq!(parser.retry, None);
+    }
+}
@@ -1,18 +1,39 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::{Future, IntoFuture},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
     response::{sse::Event, IntoResponse, Sse},
     routing::{get, post},
     Json,
 };
-use serde::Deserialize;
-use tokio::net::TcpListener;
-use tokio_stream::{wrappers::errors::BroadcastStreamRecvError, Stream, StreamExt};
-use tower_http::cors::{self, CorsLayer};
-use tracing::level_filters::LevelFilter;
+use futures_util::FutureExt as _;
+use pin_project::{pin_project, pinned_drop};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, watch},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    Stream, StreamExt,
+};
+use tower_http::{
+    cors::{self, CorsLayer},
+    metrics::{in_flight_requests::InFlightRequestsCounter, InFlightRequestsLayer},
+};
+use tracing::{level_filters::LevelFilter, Span};
 use tracing_subscriber::{
     fmt::{format::FmtSpan, Layer},
     layer::SubscriberExt as _,
@@ -20,32 +41,489 @@ use tracing_subscriber::{
     Layer as _,
 };
 
+/// 广播的消息 订阅者按事件名过滤后再转换为Event
+#[derive(Debug, Clone, Serialize)]
+struct SseMessage {
+    /// 事件id 格式为<启动标识>-<序号> 客户端重连时通过Last-Event-ID带回
+    id: String,
+    #[serde(skip)]
+    seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    data: String,
+}
+
+impl SseMessage {
+    /// 未指定事件名时 按SSE规范视为message
+    fn event_name(&self) -> &str {
+        self.event.as_deref().unwrap_or("message")
+    }
+
+    fn into_event(self) -> Event {
+        let event = Event::default().id(self.id).data(self.data);
+        match self.event {
+            Some(name) => event.event(name),
+            None => event,
+        }
+    }
+}
+
+/// 消息分发方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ChannelMode {
+    /// 共享一个广播通道 慢的订阅者落后太多时会丢失消息
+    #[default]
+    Broadcast,
+    /// 每个订阅者一个有界通道 由分发任务逐个投递 慢的订阅者会拖慢发布者而不是丢消息
+    Mpsc,
+}
+
+/// 实际使用的通道 Clone出来的发送端只在单次发送期间持有
+#[derive(Clone)]
+enum Channel {
+    Broadcast(broadcast::Sender<SseMessage>),
+    Mpsc {
+        // 发布者写入 分发任务读取
+        input: mpsc::Sender<SseMessage>,
+        subscribers: Arc<Mutex<Vec<mpsc::Sender<SseMessage>>>>,
+    },
+}
+
+/// 最近发布的消息 断线重连时按Last-Event-ID补发
+#[derive(Debug)]
+struct History {
+    /// 本次启动的标识 重启后旧的事件id不会被误认为当前的序号
+    epoch: String,
+    next_seq: u64,
+    capacity: usize,
+    messages: VecDeque<SseMessage>,
+}
+
+impl History {
+    fn new(capacity: usize) -> Self {
+        Self {
+            epoch: uuid::Uuid::new_v4().simple().to_string()[..8].to_string(),
+            next_seq: 1,
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// 分配序号并记录 超出容量时丢弃最早的消息
+    fn record(&mut self, event: Option<String>, data: String) -> SseMessage {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let message = SseMessage {
+            id: format!("{}-{}", self.epoch, seq),
+            seq,
+            event,
+            data,
+        };
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message.clone());
+        message
+    }
+
+    /// 解析本次启动分配的事件id 其他启动的id返回None
+    fn parse_seq(&self, id: &str) -> Option<u64> {
+        let (epoch, seq) = id.split_once('-')?;
+        (epoch == self.epoch).then_some(())?;
+        seq.parse().ok()
+    }
+
+    /// 序号大于seq的消息 最早的消息已被丢弃时补发会有缺口
+    fn since(&self, seq: u64) -> Vec<SseMessage> {
+        if let Some(oldest) = self.messages.front() {
+            if oldest.seq > seq + 1 {
+                tracing::warn!(
+                    "Replay from {} is incomplete, history starts at {}",
+                    seq,
+                    oldest.seq
+                );
+            }
+        }
+        self.messages
+            .iter()
+            .filter(|message| message.seq > seq)
+            .cloned()
+            .collect()
+    }
+
+    /// 最后分配的序号
+    fn newest(&self) -> u64 {
+        self.next_seq - 1
+    }
+}
+
 /// 包装广播通道
 struct BroadcastWrapper {
-    sender: tokio::sync::broadcast::Sender<Event>,
+    /// 关闭后为None 订阅者的Stream在发送端全部释放后结束
+    channel: Mutex<Option<Channel>>,
+    /// 最近发布的消息 编号和写入通道在同一把锁内完成 订阅者收到的序号递增
+    history: Mutex<History>,
+    /// 缓冲区中未被所有订阅者读取的消息数达到该值时告警
+    warn_threshold: usize,
+    /// 缓冲区从何时开始超过阈值 持续超过BUFFER_WARN_AFTER才告警
+    above_since: Mutex<Option<Instant>>,
+    /// 是否处于告警状态 避免每条消息都输出告警
+    warned: AtomicBool,
 }
 
 impl BroadcastWrapper {
-    pub fn new() -> Self {
-        let (sender, receiver) = tokio::sync::broadcast::channel(10);
-        // Leak掉Receiver 否则Sender会被回收掉
-        Box::leak(receiver.into());
-        Self { sender }
+    pub fn new(mode: ChannelMode, warn_ratio: f64, history_size: usize) -> Self {
+        let channel = match mode {
+            // Sender由Wrapper持有 没有订阅者时发送会返回错误 不需要保留Receiver
+            ChannelMode::Broadcast => Channel::Broadcast(broadcast::channel(BROADCAST_CAPACITY).0),
+            ChannelMode::Mpsc => {
+                let (input, receiver) = mpsc::channel(BROADCAST_CAPACITY);
+                let subscribers = Arc::new(Mutex::new(Vec::new()));
+                tokio::spawn(fan_out(receiver, subscribers.clone()));
+                Channel::Mpsc { input, subscribers }
+            }
+        };
+        let warn_threshold = ((BROADCAST_CAPACITY as f64 * warn_ratio).ceil() as usize).max(1);
+        Self {
+            channel: Mutex::new(Some(channel)),
+            history: Mutex::new(History::new(history_size)),
+            warn_threshold,
+            above_since: Mutex::new(None),
+            warned: AtomicBool::new(false),
+        }
+    }
+    /// 发送消息 向通道中发送消息 返回收到消息的订阅者数量
+    /// 没有订阅者时消息仍然记录在历史中 重连的订阅者可以补发
+    pub async fn send(&self, event: Option<String>, message: String) -> usize {
+        let Some(channel) = self.channel() else {
+            return 0;
+        };
+        let delivered_to = match &channel {
+            Channel::Broadcast(sender) => {
+                let mut history = self.history.lock().unwrap();
+                let message = history.record(event, message);
+                sender.send(message).unwrap_or(0)
+            }
+            Channel::Mpsc { input, subscribers } => {
+                // 分发任务积压时在这里等待 背压传递给发布者
+                // 先占用通道的位置 编号和写入之间不会插入其他消息
+                match input.reserve().await {
+                    Ok(permit) => {
+                        let mut history = self.history.lock().unwrap();
+                        let message = history.record(event, message);
+                        // 先移除已经断开的订阅者 返回的数量只包括在线的订阅者
+                        let count = {
+                            let mut subscribers = subscribers.lock().unwrap();
+                            subscribers.retain(|subscriber| !subscriber.is_closed());
+                            subscribers.len()
+                        };
+                        permit.send(message);
+                        count
+                    }
+                    Err(_) => 0,
+                }
+            }
+        };
+
+        self.check_buffer();
+        delivered_to
+    }
+
+    /// 在deadline之前等待广播缓冲区有空位 超时返回false mpsc模式在发送时已经有背压
+    async fn wait_for_room(&self, deadline: tokio::time::Instant) -> bool {
+        if !matches!(self.channel(), Some(Channel::Broadcast(_))) {
+            return true;
+        }
+        tokio::time::timeout_at(deadline, async {
+            while self.buffered() >= BROADCAST_CAPACITY {
+                tokio::time::sleep(BACKPRESSURE_POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// 缓冲区中还未分发的消息数
+    fn buffered(&self) -> usize {
+        match self.channel() {
+            Some(Channel::Broadcast(sender)) => sender.len(),
+            Some(Channel::Mpsc { input, .. }) => input.max_capacity() - input.capacity(),
+            None => 0,
+        }
+    }
+
+    fn channel(&self) -> Option<Channel> {
+        self.channel.lock().unwrap().clone()
     }
-    /// 发送消息 向通道中发送消息
-    pub async fn send(&self, message: String) {
-        self.sender.send(Event::default().data(message)).unwrap();
+
+    /// 关闭通道 之后的发送直接返回0 订阅者收完已经发布的消息后Stream结束
+    pub fn close(&self) {
+        self.channel.lock().unwrap().take();
     }
 
-    /// 订阅Sender获取Receiver
-    pub fn receiver(&self) -> tokio::sync::broadcast::Receiver<Event> {
-        self.sender.subscribe()
+    /// 缓冲区积压过多说明有订阅者消费太慢或发布太快
+    /// 持续超过阈值BUFFER_WARN_AFTER后告警一次 短暂的突发不告警 恢复时记录一次
+    fn check_buffer(&self) {
+        let len = self.buffered();
+        let mut above_since = self.above_since.lock().unwrap();
+        if len >= self.warn_threshold {
+            let since = *above_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= BUFFER_WARN_AFTER && !self.warned.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "Broadcast buffer {}/{} above threshold {}, subscribers are falling behind",
+                    len,
+                    BROADCAST_CAPACITY,
+                    self.warn_threshold
+                );
+            }
+        } else {
+            *above_since = None;
+            if self.warned.swap(false, Ordering::Relaxed) {
+                tracing::info!("Broadcast buffer back to {}/{}", len, BROADCAST_CAPACITY);
+            }
+        }
+    }
+
+    /// 订阅消息 通道关闭时Stream结束
+    /// 带有本次启动的Last-Event-ID时 先补发历史中之后的消息
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<&str>,
+    ) -> Pin<Box<dyn Stream<Item = SseMessage> + Send>> {
+        // 先订阅再读取历史 两者之间发布的消息会同时出现在历史和通道中 按序号去重
+        let live = self.subscribe_live();
+        let (replay, newest) = {
+            let history = self.history.lock().unwrap();
+            let replay = last_event_id
+                .and_then(|id| history.parse_seq(id))
+                .map(|seq| history.since(seq))
+                .unwrap_or_default();
+            (replay, history.newest())
+        };
+        let live = live.filter(move |message| message.seq > newest);
+        Box::pin(tokio_stream::iter(replay).chain(live))
+    }
+
+    fn subscribe_live(&self) -> Pin<Box<dyn Stream<Item = SseMessage> + Send>> {
+        let Some(channel) = self.channel() else {
+            return Box::pin(tokio_stream::empty());
+        };
+        match channel {
+            Channel::Broadcast(sender) => {
+                let stream = BroadcastStream::new(sender.subscribe());
+                Box::pin(stream.filter_map(|result| match result {
+                    Ok(message) => Some(message),
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        tracing::warn!("Subscriber lagged, {} messages skipped", skipped);
+                        None
+                    }
+                }))
+            }
+            Channel::Mpsc { subscribers, .. } => {
+                let (sender, receiver) = mpsc::channel(BROADCAST_CAPACITY);
+                subscribers.lock().unwrap().push(sender);
+                Box::pin(ReceiverStream::new(receiver))
+            }
+        }
+    }
+}
+
+/// 按顺序把消息投递给每个订阅者 订阅者的通道满时等待 断开的订阅者被移除
+/// 发布端关闭后结束 订阅者的通道随之关闭
+async fn fan_out(
+    mut receiver: mpsc::Receiver<SseMessage>,
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<SseMessage>>>>,
+) {
+    while let Some(message) = receiver.recv().await {
+        let targets = subscribers.lock().unwrap().clone();
+        for target in targets {
+            // 发送失败说明订阅者已经断开 下面统一移除
+            let _ = target.send(message.clone()).await;
+        }
+        subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| !subscriber.is_closed());
+    }
+}
+
+/// 广播通道的容量 订阅者落后超过该数量时会丢失消息
+const BROADCAST_CAPACITY: usize = 10;
+/// 客户端重连时携带最后收到的事件id的Header
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+/// 默认保留的历史消息数 用于断线重连时补发
+const DEFAULT_HISTORY_SIZE: usize = 100;
+/// 默认的缓冲区告警比例
+const DEFAULT_BUFFER_WARN_RATIO: f64 = 0.8;
+/// 缓冲区持续超过告警阈值多久后才告警
+const BUFFER_WARN_AFTER: Duration = Duration::from_secs(1);
+/// 默认的客户端重连间隔
+const DEFAULT_RETRY_MS: u64 = 3000;
+/// 默认的CORS预检缓存时间 浏览器在此期间不再重复发送OPTIONS
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 600;
+/// 默认的停机宽限时间 SSE连接不会主动结束 超时后强制关闭
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+/// 批量模式下单帧最多包含的事件数
+const MAX_BATCH_SIZE: usize = 100;
+/// 批量发布单次最多的消息数
+const MAX_PUBLISH_BATCH: usize = 400;
+/// 批量发布时等待广播缓冲区空位的检查间隔
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// 整批发布最多等待缓冲区空位的时间 订阅者一直不读取时不会让发布者永远等待
+const BACKPRESSURE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 默认单条消息的最大字节数 过大的消息会产生巨大的SSE帧
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// 默认暂停期间最多缓冲的消息数
+const DEFAULT_PAUSE_BUFFER: usize = 100;
+
+/// 订阅者暂停期间收到的消息如何处理
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum PausePolicy {
+    /// 放入有界缓冲区 恢复后按顺序补发 缓冲区满时丢弃最早的消息
+    #[default]
+    Buffer,
+    /// 直接丢弃
+    Drop,
+}
+
+/// 服务配置
+#[derive(Debug, Clone)]
+struct SseConfig {
+    /// 客户端断开后的重连间隔
+    retry: Duration,
+    /// 批量窗口 窗口内到达的事件合并为一帧 为空时逐条发送
+    batch_window: Option<Duration>,
+    /// CORS预检结果的缓存时间
+    cors_max_age: Duration,
+    /// 停机宽限时间
+    shutdown_grace: Duration,
+    /// 广播缓冲区的告警比例
+    buffer_warn_ratio: f64,
+    /// 消息分发方式
+    channel_mode: ChannelMode,
+    /// 单条消息的最大字节数
+    max_message_size: usize,
+    /// 订阅者暂停期间的消息处理方式
+    pause_policy: PausePolicy,
+    /// 暂停期间最多缓冲的消息数
+    pause_buffer: usize,
+    /// 保留的历史消息数
+    history_size: usize,
+}
+
+impl SseConfig {
+    fn from_env() -> Self {
+        let retry_ms = std::env::var("SSE_RETRY_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_RETRY_MS);
+
+        let batch_window = std::env::var("SSE_BATCH_WINDOW_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+
+        let cors_max_age = std::env::var("CORS_MAX_AGE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_CORS_MAX_AGE_SECS);
+
+        let shutdown_grace = std::env::var("SHUTDOWN_GRACE_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+
+        let buffer_warn_ratio = std::env::var("SSE_BUFFER_WARN_RATIO")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|ratio: &f64| *ratio > 0.0 && *ratio <= 1.0)
+            .unwrap_or(DEFAULT_BUFFER_WARN_RATIO);
+
+        let channel_mode = match std::env::var("SSE_CHANNEL_MODE").as_deref() {
+            Ok("mpsc") => ChannelMode::Mpsc,
+            _ => ChannelMode::Broadcast,
+        };
+
+        let max_message_size = std::env::var("SSE_MAX_MESSAGE_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+
+        let pause_policy = match std::env::var("SSE_PAUSE_POLICY").as_deref() {
+            Ok("drop") => PausePolicy::Drop,
+            _ => PausePolicy::Buffer,
+        };
+
+        let pause_buffer = std::env::var("SSE_PAUSE_BUFFER")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_PAUSE_BUFFER);
+
+        let history_size = std::env::var("SSE_HISTORY_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_HISTORY_SIZE);
+
+        Self {
+            retry: Duration::from_millis(retry_ms),
+            batch_window,
+            cors_max_age: Duration::from_secs(cors_max_age),
+            shutdown_grace: Duration::from_secs(shutdown_grace),
+            buffer_warn_ratio,
+            channel_mode,
+            max_message_size,
+            pause_policy,
+            pause_buffer,
+            history_size,
+        }
     }
 }
 
+/// 状态
+struct AppState {
+    broadcast_wrapper: BroadcastWrapper,
+    config: SseConfig,
+    /// 在线订阅者的暂停开关 key为订阅id
+    subscribers: Mutex<HashMap<String, watch::Sender<bool>>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SsePayload {
     pub message: String,
+    /// 事件名 为空时使用默认的message事件
+    pub event: Option<String>,
+}
+
+/// 批量发布 每条消息作为单独的事件按顺序发布
+#[derive(Debug, Deserialize)]
+pub struct BatchPayload {
+    pub messages: Vec<String>,
+    /// 所有消息共用的事件名 为空时使用默认的message事件
+    pub event: Option<String>,
+}
+
+/// 批量发布结果
+#[derive(Debug, Serialize)]
+pub struct BatchAck {
+    /// 发布的消息数量
+    pub published: usize,
+}
+
+/// 发布结果
+#[derive(Debug, Serialize)]
+pub struct PublishAck {
+    /// 收到消息的订阅者数量
+    pub delivered_to: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SseQuery {
+    /// 逗号分隔的事件名 只接收这些事件
+    pub types: Option<String>,
 }
 
 #[tokio::main]
@@ -59,46 +537,1006 @@ async fn main() -> Result<()> {
 
     let addr = "0.0.0.0:3000";
 
-    let cors_layer = CorsLayer::new().allow_origin(cors::Any);
+    let config = SseConfig::from_env();
 
-    let state = Arc::new(BroadcastWrapper::new());
+    let state = Arc::new(AppState {
+        broadcast_wrapper: BroadcastWrapper::new(
+            config.channel_mode,
+            config.buffer_warn_ratio,
+            config.history_size,
+        ),
+        config,
+        subscribers: Mutex::new(HashMap::new()),
+    });
 
-    let app = axum::Router::new()
-        .route("/", post(send_msg))
-        .route("/sse", get(sse_handler))
-        .layer(cors_layer)
-        .with_state(state);
+    // 统计进行中的请求 每个SSE订阅都是一个进行中的请求
+    let (in_flight_layer, in_flight) = InFlightRequestsLayer::pair();
+
+    let app = app(state.clone()).layer(in_flight_layer);
 
     let listener = TcpListener::bind(addr).await?;
     tracing::info!("Listening on: {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    serve_with_grace(listener, app, state, in_flight, shutdown_signal()).await
+}
+
+/// 收到Ctrl+C后不再接受新连接并关闭通道 订阅者收到error事件后断开 超过宽限时间后强制关闭剩余的订阅
+async fn serve_with_grace(
+    listener: TcpListener,
+    app: axum::Router,
+    state: Arc<AppState>,
+    in_flight: InFlightRequestsCounter,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    // 同一个停机信号既停止接受连接 也开始计算宽限时间
+    let shutdown = shutdown.boxed().shared();
+    let shutdown_grace = state.config.shutdown_grace;
+    let server = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown.await;
+                tracing::info!("Shutting down");
+                state.broadcast_wrapper.close();
+            }
+        })
+        .into_future();
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            shutdown.await;
+            tokio::time::sleep(shutdown_grace).await;
+        } => {
+            tracing::warn!(
+                "Shutdown grace period elapsed, force closing {} in-flight requests",
+                in_flight.get()
+            );
+        }
+    }
 
     Ok(())
 }
 
-/// 发送消息
+/// 路由 允许任意来源跨域订阅
+fn app(state: Arc<AppState>) -> axum::Router {
+    let cors_layer = CorsLayer::new()
+        .allow_origin(cors::Any)
+        .max_age(state.config.cors_max_age);
+
+    axum::Router::new()
+        .route("/", post(send_msg))
+        .route("/batch", post(send_batch))
+        .route("/sse", get(sse_handler))
+        .route("/sse/:id/pause", post(pause_subscriber))
+        .route("/sse/:id/resume", post(resume_subscriber))
+        .layer(cors_layer)
+        .with_state(state)
+}
+
+/// 等待Ctrl+C
+async fn shutdown_signal() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        tracing::warn!("Listen shutdown signal error: {}", err);
+        // 无法监听信号时不触发停机
+        std::future::pending::<()>().await;
+    }
+}
+
+/// 发送消息 返回送达的订阅者数量 没有订阅者时返回202
+/// 消息超过大小限制时返回413 不会广播
 async fn send_msg(
-    broadcast_wrapper: State<Arc<BroadcastWrapper>>,
+    state: State<Arc<AppState>>,
     Json(payload): Json<SsePayload>,
 ) -> impl IntoResponse {
-    broadcast_wrapper.send(payload.message).await;
+    let max_message_size = state.config.max_message_size;
+    if payload.message.len() > max_message_size {
+        tracing::warn!(
+            "Rejected message of {} bytes, limit is {}",
+            payload.message.len(),
+            max_message_size
+        );
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("message exceeds {} bytes", max_message_size),
+        )
+            .into_response();
+    }
+
+    let event = payload.event.clone();
+    let size = payload.message.len();
+    let delivered_to = state
+        .broadcast_wrapper
+        .send(payload.event, payload.message)
+        .await;
+    tracing::info!(
+        event = event.as_deref().unwrap_or("message"),
+        size,
+        delivered_to,
+        "Published message"
+    );
+
+    let status = if delivered_to == 0 {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+
+    (status, Json(PublishAck { delivered_to })).into_response()
+}
+
+/// 批量发送消息 任意一条超过大小限制时整批拒绝 不会只发布一部分
+async fn send_batch(
+    state: State<Arc<AppState>>,
+    Json(payload): Json<BatchPayload>,
+) -> impl IntoResponse {
+    if payload.messages.len() > MAX_PUBLISH_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("at most {} messages per batch", MAX_PUBLISH_BATCH),
+        )
+            .into_response();
+    }
+    let max_message_size = state.config.max_message_size;
+    if let Some(index) = payload
+        .messages
+        .iter()
+        .position(|message| message.len() > max_message_size)
+    {
+        tracing::warn!("Rejected batch, message {} is too large", index);
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("message {} exceeds {} bytes", index, max_message_size),
+        )
+            .into_response();
+    }
+
+    // 广播通道没有背压 每条消息发送前等待缓冲区有空位 一次大批量不会让订阅者落后丢消息
+    // 整批共用一个截止时间 超时一次后剩余的消息直接发送 停滞的订阅者不会让每条消息都等待
+    let published = payload.messages.len();
+    let deadline = tokio::time::Instant::now() + BACKPRESSURE_TIMEOUT;
+    let mut waiting = true;
+    for message in payload.messages {
+        if waiting && !state.broadcast_wrapper.wait_for_room(deadline).await {
+            tracing::warn!("Broadcast buffer still full, slow subscribers will lag");
+            waiting = false;
+        }
+        state
+            .broadcast_wrapper
+            .send(payload.event.clone(), message)
+            .await;
+    }
+    tracing::info!(
+        event = payload.event.as_deref().unwrap_or("message"),
+        published,
+        "Published batch"
+    );
 
-    StatusCode::OK
+    Json(BatchAck { published }).into_response()
+}
+
+/// 暂停订阅者的推送 暂停期间的消息按配置缓冲或丢弃
+async fn pause_subscriber(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    set_paused(&state, &id, true)
+}
+
+/// 恢复订阅者的推送 缓冲的消息按顺序补发
+async fn resume_subscriber(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    set_paused(&state, &id, false)
+}
+
+/// 订阅者不存在或已经断开时返回404
+fn set_paused(state: &AppState, id: &str, paused: bool) -> StatusCode {
+    match state.subscribers.lock().unwrap().get(id) {
+        Some(control) => {
+            // 状态没有变化时不通知订阅者
+            control.send_if_modified(|current| std::mem::replace(current, paused) != paused);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
 }
 
 /// 注册SSR通道
 async fn sse_handler(
-    State(broadcast_wrapper): State<Arc<BroadcastWrapper>>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SseQuery>,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, BroadcastStreamRecvError>>> {
-    // 将Broadcast Receiver转换为Stream
-    let stream = tokio_stream::wrappers::BroadcastStream::new(broadcast_wrapper.receiver());
-    // 过滤掉错误的消息
-    let stream = stream.filter_map(|result| match result {
-        Ok(item) => Some(Ok(item)),
-        Err(_) => None,
+    // 每个订阅一个span 订阅期间的日志都带上订阅id
+    let subscriber_id = uuid::Uuid::new_v4().to_string();
+    let span = tracing::info_span!("sse_subscriber", subscriber_id = %subscriber_id);
+    // 解析订阅的事件类型 未指定时接收全部
+    let types: Option<HashSet<String>> = query.types.map(|types| {
+        types
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect()
     });
 
+    // 重连的客户端带上最后收到的事件id 补发断开期间的消息
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    // 过滤掉未订阅的事件
+    let stream =
+        state
+            .broadcast_wrapper
+            .subscribe(last_event_id)
+            .filter(move |message| match &types {
+                Some(types) => types.contains(message.event_name()),
+                None => true,
+            });
+
+    // 注册暂停开关 订阅者可以通过订阅id暂停和恢复自己的推送
+    let (control, paused) = watch::channel(false);
+    state
+        .subscribers
+        .lock()
+        .unwrap()
+        .insert(subscriber_id.clone(), control);
+    let stream = Pausable::new(
+        stream,
+        paused,
+        &state.config,
+        Registration {
+            state: state.clone(),
+            subscriber_id: subscriber_id.clone(),
+        },
+    )
+    .into_stream();
+
+    // 开启批量模式时 将窗口内的事件合并为一个JSON数组帧
+    let stream: Pin<Box<dyn Stream<Item = Event> + Send>> = match state.config.batch_window {
+        Some(window) => Box::pin(
+            stream
+                .chunks_timeout(MAX_BATCH_SIZE, window)
+                .map(batch_event),
+        ),
+        None => Box::pin(stream.map(SseMessage::into_event)),
+    };
+    let stream = stream.map(Ok);
+
+    // 广播通道关闭时BroadcastStream会直接结束 追加一个error事件 让客户端能区分于正常结束
+    let stream = stream.chain(futures_util::stream::once(async {
+        tracing::warn!("Broadcast channel closed, ending sse stream");
+        Ok(channel_closed_event())
+    }));
+
+    // 统计送达的事件数 客户端断开时记录访问日志
+    let stream = TrackedStream::new(stream, span);
+
+    // 连接建立后立即发送connected事件 告知客户端订阅id
+    let connected = Event::default().event("connected").data(subscriber_id);
+    let stream = tokio_stream::once(Ok(connected)).chain(stream);
+
+    // 第一帧告知浏览器断线后的重连间隔
+    let retry = Event::default().retry(state.config.retry);
+    let stream = tokio_stream::once(Ok(retry)).chain(stream);
+
     // 返回Sse Stream
     Sse::new(stream)
 }
+
+/// 订阅的访问日志 轮询时进入订阅的span 被Drop时说明客户端已经断开
+/// tracing的Instrument只支持Future 这里为Stream做同样的事情
+#[pin_project(PinnedDrop)]
+struct TrackedStream<S> {
+    #[pin]
+    inner: S,
+    span: Span,
+    connected_at: Instant,
+    delivered: u64,
+}
+
+impl<S> TrackedStream<S> {
+    fn new(inner: S, span: Span) -> Self {
+        span.in_scope(|| tracing::info!("Subscriber connected"));
+        Self {
+            inner,
+            span,
+            connected_at: Instant::now(),
+            delivered: 0,
+        }
+    }
+}
+
+impl<S: Stream> Stream for TrackedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let _enter = this.span.enter();
+        let item = ready!(this.inner.poll_next(cx));
+        if item.is_some() {
+            *this.delivered += 1;
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[pinned_drop]
+impl<S> PinnedDrop for TrackedStream<S> {
+    fn drop(self: Pin<&mut Self>) {
+        let _enter = self.span.enter();
+        tracing::info!(
+            delivered = self.delivered,
+            duration_ms = self.connected_at.elapsed().as_millis() as u64,
+            "Subscriber disconnected"
+        );
+    }
+}
+
+/// 订阅者在AppState中的注册 Stream被Drop时移除暂停开关
+struct Registration {
+    state: Arc<AppState>,
+    subscriber_id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.state
+            .subscribers
+            .lock()
+            .unwrap()
+            .remove(&self.subscriber_id);
+    }
+}
+
+/// 可以暂停的订阅 暂停期间仍然读取通道 避免订阅者在广播通道中落后
+struct Pausable<S> {
+    inner: S,
+    paused: watch::Receiver<bool>,
+    policy: PausePolicy,
+    capacity: usize,
+    buffer: VecDeque<SseMessage>,
+    // 本次暂停期间丢弃的消息数
+    dropped: u64,
+    _registration: Registration,
+}
+
+impl<S> Pausable<S>
+where
+    S: Stream<Item = SseMessage> + Unpin + Send + 'static,
+{
+    fn new(
+        inner: S,
+        paused: watch::Receiver<bool>,
+        config: &SseConfig,
+        registration: Registration,
+    ) -> Self {
+        Self {
+            inner,
+            paused,
+            policy: config.pause_policy,
+            capacity: config.pause_buffer,
+            buffer: VecDeque::new(),
+            dropped: 0,
+            _registration: registration,
+        }
+    }
+
+    fn into_stream(self) -> impl Stream<Item = SseMessage> + Send {
+        futures_util::stream::unfold(self, |mut this| async move {
+            let message = this.next().await?;
+            Some((message, this))
+        })
+    }
+
+    /// 未暂停时先补发缓冲的消息 再读取通道
+    async fn next(&mut self) -> Option<SseMessage> {
+        loop {
+            if !*self.paused.borrow() {
+                if let Some(message) = self.buffer.pop_front() {
+                    return Some(message);
+                }
+            }
+
+            tokio::select! {
+                message = self.inner.next() => {
+                    let message = message?;
+                    if *self.paused.borrow() {
+                        self.hold(message);
+                    } else if self.buffer.is_empty() {
+                        return Some(message);
+                    } else {
+                        // 等待期间已经恢复 缓冲的消息还没有补发 新消息排在后面
+                        self.buffer.push_back(message);
+                    }
+                }
+                changed = self.paused.changed() => {
+                    // 开关随Registration一起移除 这里不会失败
+                    changed.ok()?;
+                    self.log_transition();
+                }
+            }
+        }
+    }
+
+    /// 暂停期间收到的消息
+    fn hold(&mut self, message: SseMessage) {
+        match self.policy {
+            PausePolicy::Buffer => {
+                if self.buffer.len() >= self.capacity {
+                    self.buffer.pop_front();
+                    self.dropped += 1;
+                }
+                self.buffer.push_back(message);
+            }
+            PausePolicy::Drop => self.dropped += 1,
+        }
+    }
+
+    fn log_transition(&mut self) {
+        if *self.paused.borrow() {
+            tracing::info!(policy = ?self.policy, "Subscriber paused");
+        } else {
+            tracing::info!(
+                buffered = self.buffer.len(),
+                dropped = self.dropped,
+                "Subscriber resumed"
+            );
+            self.dropped = 0;
+        }
+    }
+}
+
+/// 广播通道关闭时发送的最后一个事件
+fn channel_closed_event() -> Event {
+    Event::default()
+        .event("error")
+        .data("broadcast channel closed")
+}
+
+/// 合并多条消息为一个batch事件 事件id使用最后一条消息的id
+fn batch_event(batch: Vec<SseMessage>) -> Event {
+    let id = batch
+        .last()
+        .map(|message| message.id.clone())
+        .unwrap_or_default();
+    Event::default()
+        .event("batch")
+        .id(id)
+        .json_data(batch)
+        .expect("serialize sse batch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next_data<S: Stream<Item = SseMessage> + Unpin>(stream: &mut S) -> String {
+        tokio::time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .expect("message in time")
+            .expect("stream open")
+            .data
+    }
+
+    #[tokio::test]
+    async fn reconnect_replays_without_gaps() {
+        for mode in [ChannelMode::Broadcast, ChannelMode::Mpsc] {
+            let wrapper = BroadcastWrapper::new(mode, DEFAULT_BUFFER_WARN_RATIO, 10);
+
+            let mut first = wrapper.subscribe(None);
+            wrapper.send(None, "m1".to_string()).await;
+            let last_id = tokio::time::timeout(Duration::from_secs(1), first.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .id;
+            drop(first);
+
+            // 断开期间发布的消息在重连后按顺序补发 之后继续接收新消息
+            wrapper.send(None, "m2".to_string()).await;
+            wrapper.send(None, "m3".to_string()).await;
+            let mut resumed = wrapper.subscribe(Some(&last_id));
+            wrapper.send(None, "m4".to_string()).await;
+            for expected in ["m2", "m3", "m4"] {
+                assert_eq!(next_data(&mut resumed).await, expected, "{:?}", mode);
+            }
+
+            // 其他启动的id不会补发
+            let mut fresh = wrapper.subscribe(Some("unknown-1"));
+            wrapper.send(None, "m5".to_string()).await;
+            assert_eq!(next_data(&mut fresh).await, "m5");
+        }
+    }
+
+    #[test]
+    fn history_drops_oldest_beyond_capacity() {
+        let mut history = History::new(2);
+        for data in ["a", "b", "c"] {
+            history.record(None, data.to_string());
+        }
+        let replay: Vec<_> = history
+            .since(0)
+            .into_iter()
+            .map(|message| message.data)
+            .collect();
+        assert_eq!(replay, ["b", "c"]);
+        assert_eq!(history.newest(), 3);
+        assert_eq!(history.parse_seq(&format!("{}-2", history.epoch)), Some(2));
+        assert_eq!(history.parse_seq("other-2"), None);
+    }
+
+    #[tokio::test]
+    async fn large_batch_does_not_lag_subscribers() {
+        let wrapper = Arc::new(BroadcastWrapper::new(
+            ChannelMode::Broadcast,
+            DEFAULT_BUFFER_WARN_RATIO,
+            10,
+        ));
+        let mut subscriber = wrapper.subscribe(None);
+
+        // 和send_batch一样逐条等待空位 批量远大于广播缓冲区
+        let publisher = tokio::spawn({
+            let wrapper = wrapper.clone();
+            async move {
+                let deadline = tokio::time::Instant::now() + BACKPRESSURE_TIMEOUT;
+                for i in 0..BROADCAST_CAPACITY * 5 {
+                    assert!(wrapper.wait_for_room(deadline).await);
+                    wrapper.send(None, i.to_string()).await;
+                }
+            }
+        });
+        for i in 0..BROADCAST_CAPACITY * 5 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            assert_eq!(next_data(&mut subscriber).await, i.to_string());
+        }
+        publisher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delivered_to_counts_live_subscribers() {
+        for mode in [ChannelMode::Broadcast, ChannelMode::Mpsc] {
+            let wrapper = BroadcastWrapper::new(mode, DEFAULT_BUFFER_WARN_RATIO, 10);
+            let live = wrapper.subscribe(None);
+            let gone = wrapper.subscribe(None);
+            drop(gone);
+            assert_eq!(wrapper.send(None, "a".to_string()).await, 1, "{:?}", mode);
+            drop(live);
+            assert_eq!(wrapper.send(None, "b".to_string()).await, 0, "{:?}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn buffer_warns_only_when_above_threshold_for_a_while() {
+        let wrapper = BroadcastWrapper::new(ChannelMode::Broadcast, 0.5, 10);
+        // 订阅后不读取 消息一直留在缓冲区中
+        let _slow = wrapper.subscribe(None);
+        for i in 0..BROADCAST_CAPACITY / 2 {
+            wrapper.send(None, i.to_string()).await;
+        }
+        // 刚超过阈值时不告警
+        assert!(!wrapper.warned.load(Ordering::Relaxed));
+
+        tokio::time::sleep(BUFFER_WARN_AFTER).await;
+        wrapper.send(None, "late".to_string()).await;
+        assert!(wrapper.warned.load(Ordering::Relaxed));
+    }
+
+    fn test_config() -> SseConfig {
+        SseConfig {
+            retry: Duration::from_millis(DEFAULT_RETRY_MS),
+            batch_window: None,
+            cors_max_age: Duration::from_secs(DEFAULT_CORS_MAX_AGE_SECS),
+            shutdown_grace: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS),
+            buffer_warn_ratio: DEFAULT_BUFFER_WARN_RATIO,
+            channel_mode: ChannelMode::Broadcast,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            pause_policy: PausePolicy::Buffer,
+            pause_buffer: DEFAULT_PAUSE_BUFFER,
+            history_size: DEFAULT_HISTORY_SIZE,
+        }
+    }
+
+    fn test_state(config: SseConfig) -> Arc<AppState> {
+        Arc::new(AppState {
+            broadcast_wrapper: BroadcastWrapper::new(
+                config.channel_mode,
+                config.buffer_warn_ratio,
+                config.history_size,
+            ),
+            config,
+            subscribers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batch_with_stalled_subscriber_finishes_within_deadline() {
+        let state = test_state(test_config());
+        // 订阅后一直不读取 缓冲区很快被占满
+        let _stalled = state.broadcast_wrapper.subscribe(None);
+
+        let messages: Vec<_> = (0..BROADCAST_CAPACITY * 3).map(|i| i.to_string()).collect();
+        let start = tokio::time::Instant::now();
+        let payload = BatchPayload {
+            messages,
+            event: None,
+        };
+        let response = send_batch(State(state.clone()), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // 只等待一次截止时间 不是每条消息都等待
+        let elapsed = start.elapsed();
+        assert!(elapsed >= BACKPRESSURE_TIMEOUT);
+        assert!(
+            elapsed < BACKPRESSURE_TIMEOUT + Duration::from_secs(1),
+            "{:?}",
+            elapsed
+        );
+        assert_eq!(
+            state.broadcast_wrapper.history.lock().unwrap().newest(),
+            BROADCAST_CAPACITY as u64 * 3
+        );
+
+        // 超过单次上限的批量直接拒绝
+        let payload = BatchPayload {
+            messages: vec!["x".to_string(); MAX_PUBLISH_BATCH + 1],
+            event: None,
+        };
+        let response = send_batch(State(state), Json(payload))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// 建立订阅 返回SSE响应体的数据流
+    async fn open_sse(state: &Arc<AppState>, types: Option<&str>) -> axum::body::BodyDataStream {
+        let query = SseQuery {
+            types: types.map(str::to_string),
+        };
+        sse_handler(State(state.clone()), Query(query), HeaderMap::new())
+            .await
+            .into_response()
+            .into_body()
+            .into_data_stream()
+    }
+
+    /// 读取下一个完整的SSE帧 去掉末尾的空行
+    async fn next_frame(body: &mut axum::body::BodyDataStream, pending: &mut String) -> String {
+        loop {
+            if let Some(end) = pending.find("\n\n") {
+                let frame = pending[..end].to_string();
+                pending.drain(..end + 2);
+                return frame;
+            }
+            let chunk = tokio::time::timeout(Duration::from_secs(1), body.next())
+                .await
+                .expect("frame in time")
+                .expect("stream open")
+                .unwrap();
+            pending.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    /// 帧中指定字段的值
+    fn field<'a>(frame: &'a str, name: &str) -> Option<&'a str> {
+        frame.lines().find_map(|line| {
+            line.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(": "))
+        })
+    }
+
+    #[tokio::test]
+    async fn closed_channel_ends_stream_with_error_event() {
+        for mode in [ChannelMode::Broadcast, ChannelMode::Mpsc] {
+            let state = test_state(SseConfig {
+                channel_mode: mode,
+                ..test_config()
+            });
+            let mut body = open_sse(&state, None).await;
+            let mut pending = String::new();
+            // retry和connected之后是正常的消息
+            next_frame(&mut body, &mut pending).await;
+            next_frame(&mut body, &mut pending).await;
+            state.broadcast_wrapper.send(None, "last".to_string()).await;
+            let frame = next_frame(&mut body, &mut pending).await;
+            assert_eq!(field(&frame, "data"), Some("last"), "{:?}", mode);
+
+            // 关闭后的发送不会送达 最后一帧是error事件 之后Stream结束
+            state.broadcast_wrapper.close();
+            assert_eq!(
+                state.broadcast_wrapper.send(None, "gone".to_string()).await,
+                0
+            );
+            let frame = next_frame(&mut body, &mut pending).await;
+            assert_eq!(
+                frame, "event: error\ndata: broadcast channel closed",
+                "{:?}",
+                mode
+            );
+            let end = tokio::time::timeout(Duration::from_secs(1), body.next()).await;
+            assert!(end.unwrap().is_none(), "{:?}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_data_frame_is_connected_event() {
+        let state = test_state(test_config());
+        let mut body = open_sse(&state, None).await;
+        let mut pending = String::new();
+        next_frame(&mut body, &mut pending).await;
+
+        let frame = next_frame(&mut body, &mut pending).await;
+        let subscriber_id = frame
+            .strip_prefix("event: connected\ndata: ")
+            .expect("connected event");
+        assert!(uuid::Uuid::parse_str(subscriber_id).is_ok());
+        // 订阅id就是暂停开关的key
+        assert!(state
+            .subscribers
+            .lock()
+            .unwrap()
+            .contains_key(subscriber_id));
+    }
+
+    #[tokio::test]
+    async fn types_query_filters_events() {
+        let state = test_state(test_config());
+        let mut body = open_sse(&state, Some("alert, ")).await;
+        let mut pending = String::new();
+        next_frame(&mut body, &mut pending).await;
+        next_frame(&mut body, &mut pending).await;
+
+        let wrapper = &state.broadcast_wrapper;
+        wrapper
+            .send(Some("chat".to_string()), "hi".to_string())
+            .await;
+        wrapper.send(None, "plain".to_string()).await;
+        wrapper
+            .send(Some("alert".to_string()), "fire".to_string())
+            .await;
+
+        // 未订阅的chat和默认的message事件都被过滤
+        let frame = next_frame(&mut body, &mut pending).await;
+        assert_eq!(field(&frame, "event"), Some("alert"), "{}", frame);
+        assert_eq!(field(&frame, "data"), Some("fire"), "{}", frame);
+    }
+
+    #[tokio::test]
+    async fn first_frame_carries_configured_retry() {
+        let state = test_state(SseConfig {
+            retry: Duration::from_millis(1500),
+            ..test_config()
+        });
+        let mut body = open_sse(&state, None).await;
+        let mut pending = String::new();
+        assert_eq!(next_frame(&mut body, &mut pending).await, "retry:1500");
+    }
+
+    #[tokio::test]
+    async fn rapid_events_arrive_as_one_batch_frame() {
+        let state = test_state(SseConfig {
+            batch_window: Some(Duration::from_millis(50)),
+            ..test_config()
+        });
+        let mut body = open_sse(&state, None).await;
+        let mut pending = String::new();
+        next_frame(&mut body, &mut pending).await;
+        next_frame(&mut body, &mut pending).await;
+
+        for data in ["a", "b", "c"] {
+            state.broadcast_wrapper.send(None, data.to_string()).await;
+        }
+
+        // 窗口内的事件合并为一个JSON数组 帧id是最后一条消息的id
+        let frame = next_frame(&mut body, &mut pending).await;
+        assert_eq!(field(&frame, "event"), Some("batch"));
+        let batch: Vec<serde_json::Value> =
+            serde_json::from_str(field(&frame, "data").unwrap()).unwrap();
+        let data: Vec<_> = batch.iter().map(|message| &message["data"]).collect();
+        assert_eq!(data, ["a", "b", "c"]);
+        assert_eq!(field(&frame, "id"), batch[2]["id"].as_str());
+    }
+
+    #[tokio::test]
+    async fn oversized_message_is_rejected_and_not_broadcast() {
+        let state = test_state(SseConfig {
+            max_message_size: 4,
+            ..test_config()
+        });
+        let mut subscriber = state.broadcast_wrapper.subscribe(None);
+        let publish = |message: &str| {
+            let payload = SsePayload {
+                message: message.to_string(),
+                event: None,
+            };
+            send_msg(State(state.clone()), Json(payload))
+        };
+
+        let response = publish("too long").await.into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(state.broadcast_wrapper.history.lock().unwrap().newest(), 0);
+
+        // 下一条收到的是限制内的消息
+        let response = publish("ok").await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_data(&mut subscriber).await, "ok");
+    }
+
+    /// 收集日志输出的Writer
+    #[derive(Debug, Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_log_reports_delivered_events() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let span = tracing::info_span!("sse_subscriber", subscriber_id = "sub-1");
+        let mut stream = TrackedStream::new(tokio_stream::iter(["a", "b", "c"]), span);
+        stream.next().await;
+        stream.next().await;
+        drop(stream);
+
+        // 断开日志在订阅的span内 带上已送达的事件数
+        let text = logs.text();
+        let line = text
+            .lines()
+            .find(|line| line.contains("Subscriber disconnected"))
+            .expect("disconnect log");
+        assert!(
+            line.contains("sse_subscriber{subscriber_id=\"sub-1\"}"),
+            "{}",
+            line
+        );
+        assert!(line.contains("delivered=2"), "{}", line);
+        assert!(line.contains("duration_ms="), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn paused_subscriber_buffers_or_drops() {
+        for policy in [PausePolicy::Buffer, PausePolicy::Drop] {
+            let state = test_state(SseConfig {
+                pause_policy: policy,
+                ..test_config()
+            });
+            let mut body = open_sse(&state, None).await;
+            let mut pending = String::new();
+            next_frame(&mut body, &mut pending).await;
+            let connected = next_frame(&mut body, &mut pending).await;
+            let id = field(&connected, "data").unwrap().to_string();
+
+            let status = pause_subscriber(State(state.clone()), Path(id.clone())).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            for data in ["a", "b"] {
+                state.broadcast_wrapper.send(None, data.to_string()).await;
+            }
+            // 暂停期间没有推送 消息仍然被读出通道
+            let idle = tokio::time::timeout(Duration::from_millis(50), body.next()).await;
+            assert!(idle.is_err(), "{:?}", policy);
+
+            let status = resume_subscriber(State(state.clone()), Path(id)).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            state.broadcast_wrapper.send(None, "c".to_string()).await;
+            let expected: &[&str] = match policy {
+                PausePolicy::Buffer => &["a", "b", "c"],
+                PausePolicy::Drop => &["c"],
+            };
+            for data in expected {
+                let frame = next_frame(&mut body, &mut pending).await;
+                assert_eq!(field(&frame, "data"), Some(*data), "{:?}", policy);
+            }
+        }
+
+        let state = test_state(test_config());
+        let status = pause_subscriber(State(state), Path("unknown".to_string())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn cors_preflight_is_cached() {
+        use tower::ServiceExt as _;
+
+        let state = test_state(SseConfig {
+            cors_max_age: Duration::from_secs(120),
+            ..test_config()
+        });
+        let request = axum::http::Request::builder()
+            .method("OPTIONS")
+            .uri("/sse")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", "GET")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers["access-control-max-age"], "120");
+        assert_eq!(headers["access-control-allow-origin"], "*");
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_subscribers_and_force_closes_the_rest() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let state = test_state(SseConfig {
+            shutdown_grace: Duration::from_millis(200),
+            ..test_config()
+        });
+        // 订阅在通道关闭后结束 永远不返回的请求只能被强制关闭
+        let (in_flight_layer, in_flight) = InFlightRequestsLayer::pair();
+        let app = app(state.clone())
+            .route("/hang", get(std::future::pending::<()>))
+            .layer(in_flight_layer);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let server = serve_with_grace(listener, app, state, in_flight.clone(), async {
+            shutdown_rx.await.ok();
+        });
+        let client = async {
+            let mut streams = Vec::new();
+            for path in ["/sse", "/hang"] {
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+                stream.write_all(request.as_bytes()).await.unwrap();
+                streams.push(stream);
+            }
+            while in_flight.get() < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            shutdown_tx.send(()).unwrap();
+
+            // 订阅者收到error事件后响应结束 空闲的连接被关闭
+            let mut sse = String::new();
+            streams[0].read_to_string(&mut sse).await.unwrap();
+            (sse, streams)
+        };
+        let (result, sse) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), server),
+            tokio::time::timeout(Duration::from_secs(5), client)
+        );
+
+        result.expect("stop after the grace period").unwrap();
+        let (sse, _streams) = sse.expect("subscriber closed before the grace period");
+        assert!(sse.contains("event: error"), "{}", sse);
+        assert!(
+            logs.text().contains("force closing 1 in-flight requests"),
+            "{}",
+            logs.text()
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use sqlx::{prelude::FromRow, PgPool};
+use template::migrate::MigrationRunner;
 
 #[derive(FromRow, Debug)]
 pub struct DemoData {
@@ -11,7 +12,9 @@ pub struct DemoData {
 pub async fn main() -> Result<()> {
     let pool = PgPool::connect(&dotenvy::var("DATABASE_URL")?).await?;
 
-    sqlx::migrate!("./migrations").run(&pool).await?;
+    MigrationRunner::new(&sqlx::migrate!("./migrations"))
+        .run(&pool)
+        .await?;
 
     println!("Migrations run successfully");
 
@@ -21,5 +24,115 @@ pub async fn main() -> Result<()> {
 
     tracing::info!("Demo data: {:?}", demo_data);
 
+    match get_demo_by_id(&pool, 1).await? {
+        Some(demo) => tracing::info!("Demo data by id: {:?}", demo),
+        None => tracing::info!("Demo data not found"),
+    }
+
+    // 按游标逐页读取 每页从上一页最后的id之后开始
+    let mut cursor = 0;
+    loop {
+        let (page, next) = get_demo_after(&pool, cursor, 10).await?;
+        tracing::info!("Demo data after {}: {:?}", cursor, page);
+        match next {
+            Some(next) => cursor = next,
+            None => break,
+        }
+    }
+
     Ok(())
 }
+
+/// 根据id查询 不存在时返回None
+pub async fn get_demo_by_id(pool: &PgPool, id: i32) -> Result<Option<DemoData>> {
+    let demo_data = sqlx::query_file_as!(DemoData, "queries/get_demo_by_id.sql", id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(demo_data)
+}
+
+/// 按id的键集分页 返回last_id之后的最多limit条数据和下一页的游标
+/// 不需要像OFFSET那样扫描跳过的行 数据量大时也能保持稳定的速度
+/// 返回的数据不足limit条时说明没有下一页 游标为None
+pub async fn get_demo_after(
+    pool: &PgPool,
+    last_id: i32,
+    limit: i64,
+) -> Result<(Vec<DemoData>, Option<i32>)> {
+    let demo_data = sqlx::query_file_as!(DemoData, "queries/get_demo_after.sql", last_id, limit)
+        .fetch_all(pool)
+        .await?;
+
+    let cursor = match demo_data.last() {
+        Some(last) if demo_data.len() as i64 == limit => Some(last.id),
+        _ => None,
+    };
+
+    Ok((demo_data, cursor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn insert_demo(pool: &PgPool, data: &str) -> i32 {
+        sqlx::query_scalar("INSERT INTO migrate_demo (data) VALUES ($1) RETURNING id")
+            .bind(data)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn get_demo_by_id_returns_none_when_missing(pool: PgPool) {
+        let id = insert_demo(&pool, "hello").await;
+
+        let demo = get_demo_by_id(&pool, id).await.unwrap().unwrap();
+        assert_eq!(demo.id, id);
+        assert_eq!(demo.data.as_deref(), Some("hello"));
+
+        assert!(get_demo_by_id(&pool, id + 1).await.unwrap().is_none());
+    }
+
+    /// 按游标读完所有页
+    async fn all_pages(pool: &PgPool, limit: i64) -> Vec<Vec<i32>> {
+        let mut pages = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (page, next) = get_demo_after(pool, cursor, limit).await.unwrap();
+            pages.push(page.iter().map(|demo| demo.id).collect());
+            match next {
+                Some(next) => cursor = next,
+                None => return pages,
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn cursor_pages_have_no_gaps_or_overlaps(pool: PgPool) {
+        let mut ids = Vec::new();
+        for i in 0..25 {
+            ids.push(insert_demo(&pool, &format!("demo-{}", i)).await);
+        }
+        // 删除的行在id序列中留下空洞 不影响分页
+        for id in [ids.remove(4), ids.remove(10)] {
+            sqlx::query("DELETE FROM migrate_demo WHERE id = $1")
+                .bind(id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let pages = all_pages(&pool, 10).await;
+        let sizes: Vec<usize> = pages.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [10, 10, 3]);
+        assert_eq!(pages.concat(), ids);
+
+        // 总数刚好是limit的整数倍时 最后多读一次空页
+        let pages = all_pages(&pool, 23).await;
+        let sizes: Vec<usize> = pages.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [23, 0]);
+        assert_eq!(pages.concat(), ids);
+    }
+}
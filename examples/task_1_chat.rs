@@ -1,16 +1,31 @@
 use core::fmt;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use dashmap::DashMap;
+use axum::{extract::State as AxumState, http::StatusCode, routing::get, Json, Router};
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use serde::Serialize;
+use template::runtime::build_runtime;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc::Sender,
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{
+        mpsc::{error::TrySendError, Receiver, Sender},
+        Notify,
+    },
 };
 
 use tokio_util::codec::{Framed, LinesCodec};
-use tracing::level_filters::LevelFilter;
+use tracing::{level_filters::LevelFilter, Instrument};
 use tracing_subscriber::{
     fmt::{format::FmtSpan, Layer},
     layer::SubscriberExt as _,
@@ -18,88 +33,1005 @@ use tracing_subscriber::{
     Layer as _,
 };
 
-/// 思路
-/// 1 监听端口
-/// 2 处理每一个链接 将Addr+Sender 保存到全局 并且将自身的信息和Receiver封装为一个Peer返回
-/// 3 当Peer进入，离开，以及收到消息时，广播给所有的Sender
+// 思路
+// 1 监听端口
+// 2 处理每一个链接 将Addr+Sender 保存到全局 并且将自身的信息和Receiver封装为一个Peer返回
+// 3 当Peer进入，离开，以及收到消息时，广播给所有的Sender
 
-/// 问题
-/// 1. 处理整条消息链路时容易混乱
-/// 2. 使用了 block_send 阻塞了整个线程 导致panic
+// 问题
+// 1. 处理整条消息链路时容易混乱
+// 2. 使用了 block_send 阻塞了整个线程 导致panic
 
-/// 用时
-/// 40分钟左右 其中查询Sink 和 SplitStream 的资料花了点时间
+// 用时
+// 40分钟左右 其中查询Sink 和 SplitStream 的资料花了点时间
 
-/// 通道内最大消息数量
+/// 默认每个Peer通道内最大积压的消息数量
 const MAX_MESSAGE_COUNT: usize = 10;
+/// 通道额外预留的容量 保证踢出时的提示一定能放入通道
+const KICK_RESERVED: usize = 2;
+/// 默认窗口内允许的溢出次数 超过后断开Peer
+const DEFAULT_OVERFLOW_LIMIT: u32 = 5;
+/// 默认统计溢出次数的窗口
+const DEFAULT_OVERFLOW_WINDOW_SECS: u64 = 10;
+/// 系统消息使用的发送者地址 不会与任何Peer冲突
+const SYSTEM_ADDR: PeerId =
+    PeerId::Tcp(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)));
+/// 新连接默认进入的房间 不计入房间数量上限
+const DEFAULT_ROOM: &str = "lobby";
+/// 默认的房间数量上限
+const DEFAULT_MAX_ROOMS: usize = 100;
+/// 默认的停机宽限时间 超时后强制断开剩余的连接
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+/// 默认每个房间每秒可以广播的消息数
+const DEFAULT_ROOM_RATE: f64 = 20.0;
+/// 默认每个房间允许的突发消息数
+const DEFAULT_ROOM_BURST: f64 = 40.0;
+/// 停机时检查剩余连接的间隔
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 强制断开后等待发送任务写出最后几行的时间 对方不读取时不会一直等待
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// 默认的用户名最大长度(字符数)
+const DEFAULT_MAX_USERNAME_LEN: usize = 32;
+/// 默认同一IP允许的同时连接数
+const MAX_CONN_PER_IP: usize = 5;
+/// 默认的单条命令处理超时(毫秒)
+const DEFAULT_COMMAND_TIMEOUT_MS: u64 = 5000;
+/// 确认模式下默认未确认的消息达到该数量时告警
+const DEFAULT_ACK_WARN_LAG: u64 = 100;
+/// 记录作者的最近消息数量 更早的消息不能再编辑或删除
+const MAX_TRACKED_MESSAGES: usize = 1000;
+
+/// 同名用户重复登录时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateLogin {
+    /// 拒绝新的连接 要求重新输入用户名
+    #[default]
+    Reject,
+    /// 踢掉旧的连接
+    Replace,
+}
+
+/// 服务配置
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    duplicate_login: DuplicateLogin,
+    max_rooms: usize,
+    // 死信通道容量 为空时不记录投递失败的消息
+    dead_letter_capacity: Option<usize>,
+    max_username_len: usize,
+    shutdown_grace: Duration,
+    // 房间级别的限流 所有成员共享
+    room_rate: f64,
+    room_burst: f64,
+    // 单条命令的处理超时 超时后回复请求者并继续读取
+    command_timeout: Duration,
+    // 每个Peer最多积压的消息数 窗口内溢出次数过多时断开
+    peer_backlog: usize,
+    overflow_limit: u32,
+    overflow_window: Duration,
+    max_conn_per_ip: usize,
+    // 登录时发送 /admin <token> 获得管理员权限 为空时不开放管理命令
+    admin_token: Option<String>,
+    // 确认模式 每行消息带上序号 客户端通过 /ack <seq> 确认
+    ack_mode: bool,
+    ack_warn_lag: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            duplicate_login: DuplicateLogin::default(),
+            max_rooms: DEFAULT_MAX_ROOMS,
+            dead_letter_capacity: None,
+            max_username_len: DEFAULT_MAX_USERNAME_LEN,
+            shutdown_grace: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS),
+            room_rate: DEFAULT_ROOM_RATE,
+            room_burst: DEFAULT_ROOM_BURST,
+            command_timeout: Duration::from_millis(DEFAULT_COMMAND_TIMEOUT_MS),
+            peer_backlog: MAX_MESSAGE_COUNT,
+            overflow_limit: DEFAULT_OVERFLOW_LIMIT,
+            overflow_window: Duration::from_secs(DEFAULT_OVERFLOW_WINDOW_SECS),
+            max_conn_per_ip: MAX_CONN_PER_IP,
+            admin_token: None,
+            ack_mode: false,
+            ack_warn_lag: DEFAULT_ACK_WARN_LAG,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let duplicate_login = match std::env::var("CHAT_DUPLICATE_LOGIN").as_deref() {
+            Ok("replace") => DuplicateLogin::Replace,
+            _ => DuplicateLogin::Reject,
+        };
+        let max_rooms = std::env::var("MAX_ROOMS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_ROOMS);
+        let dead_letter_capacity = std::env::var("CHAT_DEAD_LETTER_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|capacity| *capacity > 0);
+        let max_username_len = std::env::var("CHAT_MAX_USERNAME_LEN")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAX_USERNAME_LEN);
+        let shutdown_grace = std::env::var("SHUTDOWN_GRACE_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+        let room_rate = std::env::var("ROOM_RATE_LIMIT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|rate| *rate > 0.0)
+            .unwrap_or(DEFAULT_ROOM_RATE);
+        let room_burst = std::env::var("ROOM_BURST")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|burst| *burst >= 1.0)
+            .unwrap_or(DEFAULT_ROOM_BURST);
+        let command_timeout = std::env::var("CHAT_COMMAND_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|timeout| *timeout > 0)
+            .unwrap_or(DEFAULT_COMMAND_TIMEOUT_MS);
+        let peer_backlog = std::env::var("CHAT_PEER_BACKLOG")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|backlog| *backlog > 0)
+            .unwrap_or(MAX_MESSAGE_COUNT);
+        let overflow_limit = std::env::var("CHAT_OVERFLOW_LIMIT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|limit| *limit > 0)
+            .unwrap_or(DEFAULT_OVERFLOW_LIMIT);
+        let overflow_window = std::env::var("CHAT_OVERFLOW_WINDOW_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|window| *window > 0)
+            .unwrap_or(DEFAULT_OVERFLOW_WINDOW_SECS);
+        let max_conn_per_ip = std::env::var("CHAT_MAX_CONN_PER_IP")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(MAX_CONN_PER_IP);
+        let admin_token = std::env::var("CHAT_ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        let ack_mode = std::env::var("CHAT_ACK_MODE").is_ok();
+        let ack_warn_lag = std::env::var("CHAT_ACK_WARN_LAG")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|lag| *lag > 0)
+            .unwrap_or(DEFAULT_ACK_WARN_LAG);
+
+        Self {
+            duplicate_login,
+            max_rooms,
+            dead_letter_capacity,
+            max_username_len,
+            shutdown_grace: Duration::from_secs(shutdown_grace),
+            room_rate,
+            room_burst,
+            command_timeout: Duration::from_millis(command_timeout),
+            peer_backlog,
+            overflow_limit,
+            overflow_window: Duration::from_secs(overflow_window),
+            max_conn_per_ip,
+            admin_token,
+            ack_mode,
+            ack_warn_lag,
+        }
+    }
+
+    /// 校验管理员令牌 未配置令牌时总是失败
+    pub fn is_admin_token(&self, token: &str) -> bool {
+        self.admin_token.as_deref() == Some(token)
+    }
+
+    /// 校验用户名 返回去掉首尾空白后的用户名 失败时返回提示
+    pub fn validate_username(&self, username: &str) -> Result<String, String> {
+        let username = username.trim();
+
+        if username.is_empty() {
+            return Err("Username cannot be empty".to_string());
+        }
+        if username.chars().count() > self.max_username_len {
+            return Err(format!(
+                "Username cannot be longer than {} characters",
+                self.max_username_len
+            ));
+        }
+        if username.chars().any(char::is_control) {
+            return Err("Username cannot contain control characters".to_string());
+        }
+
+        Ok(username.to_string())
+    }
+}
+
+/// 登记用户名的结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum Login {
+    Accepted,
+    /// 踢掉了同名的旧连接
+    Replaced,
+    Rejected,
+}
+
+/// 保存在全局的Peer信息
+#[derive(Debug)]
+struct PeerHandle {
+    sender: Sender<String>,
+    username: String,
+    room: String,
+    // 通知连接的读取循环退出
+    kicked: Arc<Notify>,
+    traffic: Arc<Traffic>,
+    acks: Arc<Acks>,
+    overflow: Mutex<Overflow>,
+    // 屏蔽的用户名 不再收到这些用户的聊天消息
+    muted: Mutex<HashSet<String>>,
+}
+
+/// 消息积压溢出的次数 按固定窗口统计
+#[derive(Debug)]
+struct Overflow {
+    count: u32,
+    window_start: Instant,
+}
+
+impl Overflow {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    /// 记录一次溢出 返回当前窗口内的溢出次数
+    fn record(&mut self, window: Duration) -> u32 {
+        if self.window_start.elapsed() >= window {
+            self.count = 0;
+            self.window_start = Instant::now();
+        }
+        self.count += 1;
+        self.count
+    }
+}
 
+/// 单个连接的收发统计 字节数包含换行符
 #[derive(Debug, Default)]
+pub struct Traffic {
+    bytes_sent: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_received: AtomicU64,
+}
+
+impl Traffic {
+    fn record_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64 + 1, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_received(&self, line: &str) {
+        self.bytes_received
+            .fetch_add(line.len() as u64 + 1, Ordering::Relaxed);
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 确认模式下单个连接的序号 序号从1开始 每个连接单独计数
+#[derive(Debug, Default)]
+pub struct Acks {
+    // 最后一条发出的序号
+    sent: AtomicU64,
+    // 客户端确认过的最大序号
+    acked: AtomicU64,
+}
+
+impl Acks {
+    fn next(&self) -> u64 {
+        self.sent.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// 确认序号 超过已发出的序号时无效 确认较小的序号不会回退
+    fn ack(&self, seq: u64) -> bool {
+        if seq > self.sent.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.acked.fetch_max(seq, Ordering::Relaxed);
+        true
+    }
+
+    /// 已发出但还没有确认的消息数
+    fn lag(&self) -> u64 {
+        let sent = self.sent.load(Ordering::Relaxed);
+        sent.saturating_sub(self.acked.load(Ordering::Relaxed))
+    }
+}
+
+/// Peer的标识 TCP连接使用对端地址 Unix Socket没有对端地址 使用连接序号
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerId {
+    Tcp(SocketAddr),
+    Unix(u64),
+}
+
+impl PeerId {
+    /// 按IP限制和封禁时使用 所有Unix Socket连接共用未指定的IPv6地址
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            PeerId::Tcp(addr) => addr.ip(),
+            PeerId::Unix(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+}
+
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerId::Tcp(addr) => write!(f, "{}", addr),
+            PeerId::Unix(id) => write!(f, "unix:{}", id),
+        }
+    }
+}
+
+impl Serialize for PeerId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// 单个连接的统计快照
+#[derive(Debug, Serialize)]
+pub struct PeerStats {
+    addr: PeerId,
+    username: String,
+    bytes_sent: u64,
+    messages_sent: u64,
+    bytes_received: u64,
+    messages_received: u64,
+    // 确认模式下未确认的消息数
+    unacked: u64,
+}
+
+/// 停机后建议客户端等待多久再重连
+const SHUTDOWN_RECONNECT_AFTER: Duration = Duration::from_secs(30);
+/// 接收太慢被断开后建议的重连等待时间
+const TOO_SLOW_RECONNECT_AFTER: Duration = Duration::from_secs(10);
+/// 被管理员踢出后建议的重连等待时间
+const KICKED_RECONNECT_AFTER: Duration = Duration::from_secs(60);
+/// 被封禁后建议的重连等待时间 封禁不会自动解除 只是让客户端不要频繁重试
+const BANNED_RECONNECT_AFTER: Duration = Duration::from_secs(3600);
+/// 同一IP连接过多被拒绝后建议的重连等待时间
+const TOO_MANY_CONNECTIONS_RECONNECT_AFTER: Duration = Duration::from_secs(30);
+
+/// 服务端主动断开连接的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// 同名用户在新的连接登录
+    Replaced,
+    /// 服务停机
+    Shutdown,
+    /// 接收太慢 消息持续积压
+    TooSlow,
+    /// 被管理员踢出
+    Kicked,
+    /// 所在的IP被管理员封禁
+    Banned,
+    /// 同一IP的连接过多 登录前被拒绝
+    TooManyConnections,
+}
+
+impl Eviction {
+    fn reason(&self) -> &'static str {
+        match self {
+            Eviction::Replaced => "Your session was replaced by a new connection",
+            Eviction::Shutdown => "Server is shutting down",
+            Eviction::TooSlow => "too slow",
+            Eviction::Kicked => "You were kicked by an admin",
+            Eviction::Banned => "You were banned by an admin",
+            Eviction::TooManyConnections => "Too many connections from your address",
+        }
+    }
+
+    /// 建议的重连等待时间 被新连接顶替时不应该重连 否则会互相踢下线
+    fn reconnect_after(&self) -> Option<Duration> {
+        match self {
+            Eviction::Replaced => None,
+            Eviction::Shutdown => Some(SHUTDOWN_RECONNECT_AFTER),
+            Eviction::TooSlow => Some(TOO_SLOW_RECONNECT_AFTER),
+            Eviction::Kicked => Some(KICKED_RECONNECT_AFTER),
+            Eviction::Banned => Some(BANNED_RECONNECT_AFTER),
+            Eviction::TooManyConnections => Some(TOO_MANY_CONNECTIONS_RECONNECT_AFTER),
+        }
+    }
+
+    /// 重连建议 格式为 RECONNECT_AFTER <秒数> <原因>
+    fn advisory(&self) -> Option<String> {
+        self.reconnect_after()
+            .map(|after| format!("RECONNECT_AFTER {} {}", after.as_secs(), self.reason()))
+    }
+}
+
+/// 房间内的消息是否可以广播
+#[derive(Debug, PartialEq, Eq)]
+pub enum RoomRate {
+    Allowed,
+    /// 超出限流 first为true表示刚刚进入限流状态
+    Limited {
+        first: bool,
+    },
+}
+
+/// 房间信息
+#[derive(Debug)]
+struct Room {
+    members: usize,
+    // 令牌桶 按时间补充令牌 每条消息消耗一个
+    tokens: f64,
+    refilled_at: Instant,
+    limited: bool,
+}
+
+impl Room {
+    fn new(burst: f64) -> Self {
+        Self {
+            members: 0,
+            tokens: burst,
+            refilled_at: Instant::now(),
+            limited: false,
+        }
+    }
+}
+
+/// 占用的IP连接名额
+#[derive(Debug)]
+struct IpConnGuard {
+    state: Arc<State>,
+    ip: IpAddr,
+}
+
+impl Drop for IpConnGuard {
+    fn drop(&mut self) {
+        if self.ip.is_unspecified() {
+            return;
+        }
+        if let Entry::Occupied(mut entry) = self.state.conns_per_ip.entry(self.ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// 投递失败的消息
+#[derive(Debug)]
+pub struct DeadLetter {
+    addr: PeerId,
+    message: Arc<Message>,
+}
+
+#[derive(Debug)]
 pub struct State {
-    map: DashMap<SocketAddr, Sender<String>>,
+    map: DashMap<PeerId, PeerHandle>,
+    // 用户名到地址的映射 用于处理重复登录
+    users: DashMap<String, PeerId>,
+    // 房间名到房间信息的映射 成员为空时移除
+    rooms: Mutex<HashMap<String, Room>>,
+    config: ServerConfig,
+    started_at: Instant,
+    // 死信通道 开启后记录无法投递的消息
+    dead_letters: Option<Sender<DeadLetter>>,
+    // 每个IP当前的连接数 包括还未登录的连接
+    conns_per_ip: DashMap<IpAddr, usize>,
+    // 被管理员封禁的IP 接受连接时检查
+    banned: Mutex<HashSet<IpAddr>>,
+    // 还在运行的发送任务数 停机时等待它们写出踢出提示
+    writers: Arc<AtomicUsize>,
+    // 确认模式下聊天消息的全局序号 从1开始
+    next_message_seq: AtomicU64,
+    // 最近消息的序号、作者和房间 按序号递增排列 用于校验编辑和删除
+    authors: Mutex<VecDeque<(u64, String, String)>>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(ServerConfig::default())
+    }
+}
+
+/// 服务器运行状态
+#[derive(Debug, Serialize)]
+pub struct ChatStats {
+    peers: usize,
+    rooms: usize,
+    uptime_secs: u64,
+    connections: Vec<PeerStats>,
 }
 
 impl State {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            map: DashMap::new(),
+            users: DashMap::new(),
+            rooms: Mutex::new(HashMap::new()),
+            config,
+            started_at: Instant::now(),
+            dead_letters: None,
+            conns_per_ip: DashMap::new(),
+            banned: Mutex::new(HashSet::new()),
+            writers: Arc::new(AtomicUsize::new(0)),
+            next_message_seq: AtomicU64::new(1),
+            authors: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// 占用一个IP的连接名额 超过上限时返回None 连接结束时Guard归还名额
+    /// Unix Socket使用未指定地址 不受限制
+    fn acquire_ip(self: &Arc<Self>, ip: IpAddr) -> Option<IpConnGuard> {
+        if !ip.is_unspecified() {
+            let mut count = self.conns_per_ip.entry(ip).or_insert(0);
+            if *count >= self.config.max_conn_per_ip {
+                return None;
+            }
+            *count += 1;
+        }
+        Some(IpConnGuard {
+            state: self.clone(),
+            ip,
+        })
+    }
+
+    /// IP是否已被封禁
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(&ip)
+    }
+
+    /// 踢出指定的用户 返回用户所在的房间 用户不在线时返回None
+    pub fn kick_user(&self, username: &str) -> Option<String> {
+        let addr = *self.users.get(username)?;
+        let room = self.map.get(&addr)?.room.clone();
+        self.kick(addr, Eviction::Kicked);
+        Some(room)
+    }
+
+    /// 封禁IP 之后来自该IP的连接会被直接拒绝 已在线的连接立即断开
+    /// 返回断开的连接数
+    pub fn ban(&self, ip: IpAddr) -> usize {
+        self.banned.lock().unwrap().insert(ip);
+
+        let online: Vec<PeerId> = self
+            .map
+            .iter()
+            .map(|peer| *peer.key())
+            .filter(|addr| addr.ip() == ip)
+            .collect();
+        for addr in &online {
+            self.kick(*addr, Eviction::Banned);
+        }
+        online.len()
+    }
+
+    /// 为聊天消息分配全局序号并记录作者 只保留最近的消息
+    fn record_message(&self, username: &str, room: &str) -> u64 {
+        let mut authors = self.authors.lock().unwrap();
+        // 在锁内分配序号 保证队列按序号递增
+        let seq = self.next_message_seq.fetch_add(1, Ordering::Relaxed);
+        authors.push_back((seq, username.to_string(), room.to_string()));
+        if authors.len() > MAX_TRACKED_MESSAGES {
+            authors.pop_front();
+        }
+        seq
+    }
+
+    /// 校验消息的作者 返回消息所在的房间 删除时不再记录这条消息
+    fn check_author(&self, seq: u64, username: &str, delete: bool) -> Result<String> {
+        let mut authors = self.authors.lock().unwrap();
+        let index = authors
+            .binary_search_by_key(&seq, |(seq, _, _)| *seq)
+            .map_err(|_| anyhow::anyhow!("Message {} not found", seq))?;
+        let (_, author, room) = &authors[index];
+        if author != username {
+            anyhow::bail!("You can only change your own messages");
+        }
+        let room = room.clone();
+        if delete {
+            authors.remove(index);
+        }
+        Ok(room)
+    }
+
+    /// 开启死信记录 投递失败的消息会发送到这个通道
+    pub fn with_dead_letters(mut self, sender: Sender<DeadLetter>) -> Self {
+        self.dead_letters = Some(sender);
+        self
+    }
+
+    /// 登记用户名 同名用户已在线时按配置拒绝或踢掉旧连接
+    pub fn register(&self, username: &str, addr: PeerId) -> Login {
+        match self.users.entry(username.to_string()) {
+            Entry::Vacant(entry) => {
+                entry.insert(addr);
+                Login::Accepted
+            }
+            Entry::Occupied(mut entry) => match self.config.duplicate_login {
+                DuplicateLogin::Reject => Login::Rejected,
+                DuplicateLogin::Replace => {
+                    let old_addr = entry.insert(addr);
+                    drop(entry);
+                    self.kick(old_addr, Eviction::Replaced);
+                    Login::Replaced
+                }
+            },
+        }
+    }
+
+    /// 强制断开Peer 先发送原因和重连建议再关闭连接
+    /// 重连建议的格式为 RECONNECT_AFTER <秒数> <原因>
+    fn kick(&self, addr: PeerId, eviction: Eviction) {
+        if let Some(handle) = self.remove_peer(addr) {
+            // 被顶替时用户名已经属于新连接 不会被移除
+            self.users
+                .remove_if(&handle.username, |_, user_addr| *user_addr == addr);
+
+            let mut lines = vec![Message::System(eviction.reason().to_string()).to_string()];
+            lines.extend(eviction.advisory());
+            for line in lines {
+                if let Err(err) = handle.sender.try_send(line) {
+                    tracing::warn!("Send Kick Message Error: {:?}", err);
+                }
+            }
+            handle.kicked.notify_one();
+        }
+    }
+
+    /// 当前状态统计
+    pub fn stats(&self) -> ChatStats {
+        ChatStats {
+            peers: self.map.len(),
+            rooms: self.rooms.lock().unwrap().len(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            connections: self
+                .map
+                .iter()
+                .map(|peer| {
+                    let traffic = &peer.traffic;
+                    PeerStats {
+                        addr: *peer.key(),
+                        username: peer.username.clone(),
+                        bytes_sent: traffic.bytes_sent.load(Ordering::Relaxed),
+                        messages_sent: traffic.messages_sent.load(Ordering::Relaxed),
+                        bytes_received: traffic.bytes_received.load(Ordering::Relaxed),
+                        messages_received: traffic.messages_received.load(Ordering::Relaxed),
+                        unacked: peer.acks.lag(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
     /// 加入
-    pub fn join(
-        &self,
-        addr: SocketAddr,
-        username: String,
-        stream: Framed<TcpStream, LinesCodec>,
-    ) -> Peer {
+    pub fn join<S>(&self, addr: PeerId, username: String, stream: Framed<S, LinesCodec>) -> Peer<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
         // 创建Channel 并插入到Map中
-        let (tx, mut rx) = tokio::sync::mpsc::channel(MAX_MESSAGE_COUNT);
-        self.map.insert(addr, tx);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.config.peer_backlog + KICK_RESERVED);
+        let kicked = Arc::new(Notify::new());
+        let traffic = Arc::new(Traffic::default());
+        let acks = Arc::new(Acks::default());
+        self.map.insert(
+            addr,
+            PeerHandle {
+                sender: tx,
+                username: username.clone(),
+                room: DEFAULT_ROOM.to_string(),
+                kicked: kicked.clone(),
+                traffic: traffic.clone(),
+                acks: acks.clone(),
+                overflow: Mutex::new(Overflow::new()),
+                muted: Mutex::new(HashSet::new()),
+            },
+        );
+        self.occupy_room(&mut self.rooms.lock().unwrap(), DEFAULT_ROOM);
 
         // 拆分Steam
         let (mut sender, receiver) = stream.split();
 
         // 监听收到的消息
+        let sent = traffic.clone();
+        let numbered = acks.clone();
+        let (ack_mode, ack_warn_lag) = (self.config.ack_mode, self.config.ack_warn_lag);
+        let writers = self.writers.clone();
+        writers.fetch_add(1, Ordering::SeqCst);
         tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if let Err(err) = sender.send(msg).await {
-                    tracing::warn!("Send Message Error: {:?}", err);
+            while let Some(mut msg) = rx.recv().await {
+                // 确认模式下每行带上序号 未确认的消息刚达到阈值时告警一次
+                if ack_mode {
+                    msg = format!("{} {}", numbered.next(), msg);
+                    if numbered.lag() == ack_warn_lag {
+                        tracing::warn!("Peer {} has {} unacked messages", addr, ack_warn_lag);
+                    }
+                }
+                let len = msg.len();
+                match sender.send(msg).await {
+                    Ok(()) => sent.record_sent(len),
+                    Err(err) => tracing::warn!("Send Message Error: {:?}", err),
                 }
             }
+            // 所有发送端都已释放 队列中的消息已经写出
+            writers.fetch_sub(1, Ordering::SeqCst);
         });
 
         // 创建并返回Peer
         Peer {
             username,
+            room: DEFAULT_ROOM.to_string(),
             stream: receiver,
+            kicked,
+            traffic,
+            acks,
+            admin: false,
+            joined_at: Instant::now(),
+        }
+    }
+
+    /// 当前的房间和成员数 按房间名排序 没有成员的房间已被移除
+    pub fn room_list(&self) -> Vec<(String, usize)> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, room)| (name.clone(), room.members))
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// 切换房间 返回之前所在的房间
+    /// 新建房间会受到数量上限的限制 最后一个成员离开的房间会被移除
+    pub fn enter_room(&self, addr: PeerId, room: &str) -> Result<String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut handle = self
+            .map
+            .get_mut(&addr)
+            .ok_or_else(|| anyhow::anyhow!("Peer {} not found", addr))?;
+
+        if handle.room == room {
+            anyhow::bail!("You are already in room {}", room);
+        }
+
+        let created = rooms
+            .keys()
+            .filter(|name| name.as_str() != DEFAULT_ROOM)
+            .count();
+        if room != DEFAULT_ROOM && !rooms.contains_key(room) && created >= self.config.max_rooms {
+            anyhow::bail!("Room limit reached, cannot create room {}", room);
+        }
+
+        self.occupy_room(&mut rooms, room);
+        let previous = std::mem::replace(&mut handle.room, room.to_string());
+        Self::release_room(&mut rooms, &previous);
+
+        Ok(previous)
+    }
+
+    /// 房间成员加一 房间不存在时创建
+    fn occupy_room(&self, rooms: &mut HashMap<String, Room>, room: &str) {
+        rooms
+            .entry(room.to_string())
+            .or_insert_with(|| Room::new(self.config.room_burst))
+            .members += 1;
+    }
+
+    /// 房间成员减一 没有成员时移除房间
+    fn release_room(rooms: &mut HashMap<String, Room>, room: &str) {
+        if let Some(info) = rooms.get_mut(room) {
+            info.members -= 1;
+            if info.members == 0 {
+                rooms.remove(room);
+            }
+        }
+    }
+
+    /// 消耗房间的一个令牌 令牌不足时丢弃消息直到补充
+    pub fn check_room_rate(&self, room: &str) -> RoomRate {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(info) = rooms.get_mut(room) else {
+            return RoomRate::Allowed;
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(info.refilled_at).as_secs_f64();
+        info.tokens = (info.tokens + elapsed * self.config.room_rate).min(self.config.room_burst);
+        info.refilled_at = now;
+
+        if info.tokens >= 1.0 {
+            info.tokens -= 1.0;
+            info.limited = false;
+            return RoomRate::Allowed;
+        }
+
+        let first = !info.limited;
+        info.limited = true;
+        RoomRate::Limited { first }
+    }
+
+    /// 从全局移除Peer 并释放所在的房间
+    fn remove_peer(&self, addr: PeerId) -> Option<PeerHandle> {
+        let (_, handle) = self.map.remove(&addr)?;
+        Self::release_room(&mut self.rooms.lock().unwrap(), &handle.room);
+        Some(handle)
+    }
+
+    /// 屏蔽或取消屏蔽某个用户 返回状态是否发生了变化
+    pub fn set_muted(&self, addr: PeerId, username: &str, muted: bool) -> bool {
+        let Some(handle) = self.map.get(&addr) else {
+            return false;
+        };
+        let mut muted_users = handle.muted.lock().unwrap();
+        if muted {
+            muted_users.insert(username.to_string())
+        } else {
+            muted_users.remove(username)
+        }
+    }
+
+    /// 私信 只发送给指定的Peer
+    /// 不等待通道的空位 对方的积压已满时丢弃 避免读循环阻塞在自己的发送通道上
+    pub fn send_to(&self, addr: PeerId, msg: Message) {
+        let Some(handle) = self.map.get(&addr) else {
+            return;
+        };
+        // 预留的容量只留给踢出时的提示
+        if handle.sender.capacity() <= KICK_RESERVED {
+            tracing::warn!("Peer {} backlog is full, private message dropped", addr);
+            self.dead_letter(addr, Arc::new(msg));
+            return;
+        }
+        if let Err(err) = handle.sender.try_send(msg.to_string()) {
+            tracing::warn!("Send Private Message Error: {:?}", err);
         }
     }
 
     /// 离开
-    pub fn leave(&self, addr: SocketAddr) {
-        self.map.remove(&addr);
+    pub fn leave(&self, addr: PeerId) {
+        if let Some(handle) = self.remove_peer(addr) {
+            // 用户名可能已经被新连接占用 只移除属于自己的记录
+            self.users
+                .remove_if(&handle.username, |_, user_addr| *user_addr == addr);
+        }
     }
 
-    /// 广播
-    pub async fn broadcast(&self, addr: SocketAddr, msg: Arc<Message>) {
+    /// 系统公告 广播给所有Peer
+    pub async fn announce(&self, text: impl Into<String>) {
+        let msg = Message::System(text.into());
+        self.broadcast(SYSTEM_ADDR, Arc::new(msg)).await;
+    }
+
+    /// 广播给所有房间
+    pub async fn broadcast(&self, addr: PeerId, msg: Arc<Message>) {
+        self.deliver(addr, None, msg).await;
+    }
+
+    /// 广播给房间内的Peer
+    pub async fn broadcast_room(&self, room: &str, addr: PeerId, msg: Arc<Message>) {
+        self.deliver(addr, Some(room), msg).await;
+    }
+
+    /// 不等待慢的Peer 积压已满时丢弃消息并记录溢出 避免拖慢整个广播
+    async fn deliver(&self, addr: PeerId, room: Option<&str>, msg: Arc<Message>) {
+        let mut failed = Vec::new();
+        let mut slow = Vec::new();
         for sender in self.map.iter() {
             if sender.key() == &addr {
                 continue;
             }
-            if let Err(err) = sender.value().send(msg.to_string()).await {
-                tracing::warn!("Broadcast Message Error: {:?}", err);
-                // 发送失败时，将Peer移除
-                self.leave(addr);
+            let handle = sender.value();
+            if room.is_some_and(|room| handle.room != room) {
+                continue;
+            }
+            if let Some(sender) = msg.sender() {
+                if handle.muted.lock().unwrap().contains(sender) {
+                    continue;
+                }
+            }
+            // 预留的容量只留给踢出时的提示
+            let result = if handle.sender.capacity() > KICK_RESERVED {
+                handle.sender.try_send(msg.to_string())
+            } else {
+                Err(TrySendError::Full(String::new()))
+            };
+            match result {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    tracing::warn!("Peer {} backlog is full, message dropped", sender.key());
+                    self.dead_letter(*sender.key(), msg.clone());
+                    let overflows = handle
+                        .overflow
+                        .lock()
+                        .unwrap()
+                        .record(self.config.overflow_window);
+                    if overflows >= self.config.overflow_limit {
+                        slow.push(*sender.key());
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("Broadcast Message Error: {:?}", err);
+                    failed.push(*sender.key());
+                }
             }
         }
+
+        // 遍历结束后再移除发送失败的Peer 遍历时持有分片的读锁
+        for peer in failed {
+            self.dead_letter(peer, msg.clone());
+            self.leave(peer);
+        }
+        for peer in slow {
+            tracing::warn!("Peer {} is too slow, evicting", peer);
+            self.kick(peer, Eviction::TooSlow);
+        }
+    }
+
+    /// 记录投递失败的消息 通道已满时丢弃 不阻塞广播
+    fn dead_letter(&self, addr: PeerId, message: Arc<Message>) {
+        let Some(dead_letters) = &self.dead_letters else {
+            return;
+        };
+        if let Err(err) = dead_letters.try_send(DeadLetter { addr, message }) {
+            tracing::warn!("Dead Letter Dropped: {:?}", err);
+        }
     }
 }
 
 #[derive(Debug)]
-pub struct Peer {
+pub struct Peer<S> {
     username: String,
-    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
+    room: String,
+    stream: SplitStream<Framed<S, LinesCodec>>,
+    kicked: Arc<Notify>,
+    traffic: Arc<Traffic>,
+    acks: Arc<Acks>,
+    // 登录时是否通过了管理员令牌的校验
+    admin: bool,
+    joined_at: Instant,
 }
 
 #[derive(Debug)]
 pub enum Message {
     Join(String),
     Leave(String),
-    Broadcast { username: String, content: String },
+    Broadcast {
+        username: String,
+        content: String,
+        // 确认模式下的全局序号 用于编辑和删除
+        seq: Option<u64>,
+    },
+    Edit {
+        seq: u64,
+        content: String,
+    },
+    Delete {
+        seq: u64,
+    },
+    System(String),
+}
+
+impl Message {
+    /// 聊天消息的发送者 系统消息和进出通知没有发送者
+    fn sender(&self) -> Option<&str> {
+        match self {
+            Message::Broadcast { username, .. } => Some(username),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Message {
@@ -110,13 +1042,21 @@ impl fmt::Display for Message {
             Message::Broadcast {
                 username,
                 content: message,
+                seq: None,
             } => write!(f, "{}: {}", username, message),
+            Message::Broadcast {
+                username,
+                content: message,
+                seq: Some(seq),
+            } => write!(f, "#{} {}: {}", seq, username, message),
+            Message::Edit { seq, content } => write!(f, "#{} (edited) {}", seq, content),
+            Message::Delete { seq } => write!(f, "#{} (deleted)", seq),
+            Message::System(text) => write!(f, "*** {} ***", text),
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     // 初始化日志
     let console_layer = Layer::new()
         .with_span_events(FmtSpan::CLOSE)
@@ -124,54 +1064,285 @@ async fn main() -> Result<()> {
         .with_filter(LevelFilter::INFO);
 
     tracing_subscriber::registry().with(console_layer).init();
+
+    build_runtime()?.block_on(run())
+}
+
+async fn run() -> Result<()> {
     // 监听端口
     let addr = "0.0.0.0:3000";
     let listener = TcpListener::bind(addr).await?;
     tracing::info!("Listening on: {}", addr);
 
     // 创建全局状态
-    let state = Arc::new(State::default());
+    let config = ServerConfig::from_env();
+    let mut state = State::new(config.clone());
+    if let Some(capacity) = config.dead_letter_capacity {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+        state = state.with_dead_letters(tx);
+        tokio::spawn(log_dead_letters(rx));
+    }
+    let state = Arc::new(state);
+
+    // 管理端口 每收到一行文本就作为系统公告广播
+    let admin_addr = "127.0.0.1:3001";
+    let admin_listener = TcpListener::bind(admin_addr).await?;
+    tracing::info!("Admin listening on: {}", admin_addr);
+    tokio::spawn(accept_admin(admin_listener, state.clone()));
+
+    // 设置了CHAT_STATS_ADDR时 启动HTTP服务暴露运行状态
+    // 设置了CHAT_STATS_LOCAL_ONLY时只监听本机 端口不变
+    if let Ok(stats_addr) = std::env::var("CHAT_STATS_ADDR") {
+        let mut stats_addr: SocketAddr = stats_addr.parse()?;
+        if std::env::var("CHAT_STATS_LOCAL_ONLY").is_ok() {
+            stats_addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let stats_listener = TcpListener::bind(stats_addr).await?;
+        tracing::info!("Stats listening on: {}", stats_addr);
+        let app = stats_app(state.clone());
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(stats_listener, app.into_make_service()).await {
+                tracing::warn!("Stats Server Error: {:?}", err);
+            }
+        });
+    }
+
+    // 设置了CHAT_UNIX_SOCKET时 同时监听Unix Socket
+    #[cfg(unix)]
+    let _unix_socket_guard = match std::env::var("CHAT_UNIX_SOCKET") {
+        Ok(path) => {
+            // 清理上次异常退出残留的socket文件
+            let _ = std::fs::remove_file(&path);
+            let unix_listener = tokio::net::UnixListener::bind(&path)?;
+            tracing::info!("Listening on unix socket: {}", path);
+            tokio::spawn(accept_unix(unix_listener, state.clone()));
+            Some(UnixSocketGuard(path.into()))
+        }
+        Err(_) => None,
+    };
+
+    tokio::select! {
+        result = accept_tcp(listener, state.clone()) => return result,
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Shutting down");
+        }
+    }
+
+    shutdown(&state).await;
+    Ok(())
+}
+
+/// 不再接受新连接后 通知在线的用户 等待他们在宽限时间内断开 超时后强制断开
+async fn shutdown(state: &State) {
+    let grace = state.config.shutdown_grace;
+    state
+        .announce(format!(
+            "Server is shutting down in {} seconds",
+            grace.as_secs()
+        ))
+        .await;
+
+    let drained = tokio::time::timeout(grace, async {
+        while !state.map.is_empty() {
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+
+    if drained.is_err() {
+        // 超过宽限时间 强制断开剩余的连接
+        let remaining: Vec<PeerId> = state.map.iter().map(|peer| *peer.key()).collect();
+        tracing::warn!(
+            "Shutdown grace period elapsed, force closing {} connections",
+            remaining.len()
+        );
+        for addr in remaining {
+            state.kick(addr, Eviction::Shutdown);
+        }
+    }
+
+    // 返回后运行时会被销毁 先等待发送任务写出踢出提示和重连建议
+    let flushed = tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, async {
+        while state.writers.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    if flushed.is_err() {
+        tracing::warn!(
+            "{} peers did not receive the shutdown notice in time",
+            state.writers.load(Ordering::SeqCst)
+        );
+    }
+}
+
+/// 运行状态的HTTP服务
+/// 只允许GET /stats和GET /livez 其他请求一律404 不暴露多余的信息
+fn stats_app(state: Arc<State>) -> Router {
+    Router::new()
+        .route("/stats", get(stats_handler).fallback(not_found))
+        .route(
+            "/livez",
+            get(|| async { StatusCode::OK }).fallback(not_found),
+        )
+        .fallback(not_found)
+        .with_state(state)
+}
 
+/// 接收TCP连接
+async fn accept_tcp(listener: TcpListener, state: Arc<State>) -> Result<()> {
     loop {
         let (socket, addr) = listener.accept().await?;
-        let state = state.clone();
-        tokio::spawn(async move {
+        spawn_connection(socket, PeerId::Tcp(addr), state.clone());
+    }
+}
+
+/// 接收Unix Socket连接
+/// Unix Socket没有SocketAddr 使用递增的连接序号作为Peer的标识
+#[cfg(unix)]
+async fn accept_unix(listener: tokio::net::UnixListener, state: Arc<State>) {
+    // u64不会在进程的生命周期内回绕 不会和在线的Peer冲突
+    let mut next_id: u64 = 0;
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                tracing::warn!("Accept Unix Connection Error: {:?}", err);
+                continue;
+            }
+        };
+        next_id += 1;
+        spawn_connection(socket, PeerId::Unix(next_id), state.clone());
+    }
+}
+
+/// 退出时删除Unix Socket文件
+#[cfg(unix)]
+struct UnixSocketGuard(std::path::PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Remove Unix Socket Error: {:?}", err);
+        }
+    }
+}
+
+fn spawn_connection<S>(socket: S, addr: PeerId, state: Arc<State>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // 每个连接一个span 同一连接的日志都带上addr和username
+    let span = tracing::info_span!("conn", %addr, username = tracing::field::Empty);
+    tokio::spawn(
+        async move {
             tracing::info!("Accept Connection: {:?}", addr);
 
             if let Err(err) = handle_connection(socket, addr, state).await {
                 tracing::warn!("Handle Connection Error: {:?}", err);
             }
-
-            #[allow(dead_code)]
-            Ok::<(), anyhow::Error>(())
-        });
-    }
+        }
+        .instrument(span),
+    );
 }
 
-async fn handle_connection(
-    socket: tokio::net::TcpStream,
-    addr: SocketAddr,
-    state: Arc<State>,
-) -> Result<()> {
+async fn handle_connection<S>(socket: S, addr: PeerId, state: Arc<State>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     // 将socket包装为Framed 每一帧通过\n来分割
     let mut stream = Framed::new(socket, LinesCodec::new());
 
-    stream.send("Please input your username:").await?;
+    // 被封禁的IP 提示后直接断开
+    if state.is_banned(addr.ip()) {
+        tracing::warn!("Rejected banned address {}", addr.ip());
+        stream.send("You are banned from this server").await?;
+        if let Some(advisory) = Eviction::Banned.advisory() {
+            stream.send(advisory).await?;
+        }
+        return Ok(());
+    }
+
+    // 同一IP的连接过多时 提示后直接断开
+    let Some(_ip_guard) = state.acquire_ip(addr.ip()) else {
+        tracing::warn!("Too many connections from {}", addr.ip());
+        stream.send(Eviction::TooManyConnections.reason()).await?;
+        if let Some(advisory) = Eviction::TooManyConnections.advisory() {
+            stream.send(advisory).await?;
+        }
+        return Ok(());
+    };
+
+    let mut admin = false;
+    let (username, login) = loop {
+        stream.send("Please input your username:").await?;
+
+        let username = match stream.next().await {
+            Some(Ok(username)) => username,
+            Some(Err(err)) => return Err(err.into()),
+            None => anyhow::bail!("No username received"),
+        };
+
+        // 输入用户名之前可以先发送 /admin <token> 获得管理员权限
+        if let Some(token) = username.strip_prefix("/admin ") {
+            admin = state.config.is_admin_token(token.trim());
+            let reply = if admin {
+                "Admin access granted"
+            } else {
+                "Invalid admin token"
+            };
+            stream.send(reply).await?;
+            continue;
+        }
+
+        let username = match state.config.validate_username(&username) {
+            Ok(username) => username,
+            Err(reason) => {
+                stream.send(reason).await?;
+                continue;
+            }
+        };
 
-    let username = match stream.next().await {
-        Some(Ok(username)) => username,
-        Some(Err(err)) => return Err(err.into()),
-        None => anyhow::bail!("No username received"),
+        match state.register(&username, addr) {
+            Login::Rejected => {
+                stream
+                    .send(format!("Username {} is already taken", username))
+                    .await?;
+            }
+            login => break (username, login),
+        }
     };
 
-    // 发送加入消息
-    let msg = Message::Join(username.clone());
-    state.broadcast(addr, Arc::new(msg)).await;
+    tracing::Span::current().record("username", username.as_str());
 
-    let mut peer = state.join(addr, username, stream);
+    // 登记之后立即加入 中间不能有await 否则断开时用户名无法释放
+    let mut peer = state.join(addr, username.clone(), stream);
+    peer.admin = admin;
+
+    // 发送加入消息
+    if login == Login::Replaced {
+        state
+            .announce(format!("{} reconnected from a new session", username))
+            .await;
+    } else {
+        let msg = Message::Join(username);
+        state.broadcast_room(&peer.room, addr, Arc::new(msg)).await;
+    }
 
     // 接收消息
-    while let Some(msg) = peer.stream.next().await {
+    let mut kicked = false;
+    loop {
+        let msg = tokio::select! {
+            msg = peer.stream.next() => msg,
+            _ = peer.kicked.notified() => {
+                kicked = true;
+                break;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
         let msg = match msg {
             Ok(msg) => msg,
             Err(err) => {
@@ -181,19 +1352,1088 @@ async fn handle_connection(
         };
 
         tracing::info!("Receive Message: {}", msg);
+        peer.traffic.record_received(&msg);
+
+        // 以/开头的是命令
+        if let Some(command) = msg.strip_prefix('/') {
+            // 命令可能阻塞在IO上 超时只影响这条命令 不断开连接
+            let timeout = state.config.command_timeout;
+            let handled =
+                tokio::time::timeout(timeout, handle_command(&state, addr, &mut peer, command))
+                    .await;
+            if handled.is_err() {
+                tracing::warn!("Command /{} timed out after {:?}", command, timeout);
+                let msg = Message::System("command timed out".to_string());
+                state.send_to(addr, msg);
+            }
+            continue;
+        }
+
+        // 房间超出限流时丢弃消息 刚进入限流时通知房间内所有人
+        match state.check_room_rate(&peer.room) {
+            RoomRate::Allowed => {}
+            RoomRate::Limited { first } => {
+                if first {
+                    let msg = Message::System("Room is rate limited".to_string());
+                    state
+                        .broadcast_room(&peer.room, SYSTEM_ADDR, Arc::new(msg))
+                        .await;
+                }
+                continue;
+            }
+        }
+
+        // 确认模式下为消息分配序号 之后可以通过序号编辑或删除
+        let seq = state
+            .config
+            .ack_mode
+            .then(|| state.record_message(&peer.username, &peer.room));
 
         // 广播消息
         let msg = Message::Broadcast {
             username: peer.username.clone(),
             content: msg,
+            seq,
         };
-        state.broadcast(addr, Arc::new(msg)).await;
+        state.broadcast_room(&peer.room, addr, Arc::new(msg)).await;
+
+        // 自己不会收到广播 单独告知作者消息的序号
+        if let Some(seq) = seq {
+            let msg = Message::System(format!("Sent #{}", seq));
+            state.send_to(addr, msg);
+        }
+    }
+
+    // 被踢出时已经从全局移除 用户名属于新的连接 不再广播离开
+    if kicked {
+        return Ok(());
     }
 
     // 当无法接受消息时 表示Peer已经离开
     state.leave(addr);
     let msg = Message::Leave(peer.username.clone());
-    state.broadcast(addr, Arc::new(msg)).await;
+    state.broadcast_room(&peer.room, addr, Arc::new(msg)).await;
 
     Ok(())
 }
+
+/// 处理命令
+async fn handle_command<S>(state: &State, addr: PeerId, peer: &mut Peer<S>, command: &str) {
+    let (name, arg) = command
+        .split_once(' ')
+        .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+
+    match name {
+        // 切换房间 /join <room>
+        "join" if !arg.is_empty() => match state.enter_room(addr, arg) {
+            Ok(previous) => {
+                // 先更新房间 即使后续广播超时被取消 Peer也和State保持一致
+                peer.room = arg.to_string();
+
+                let msg = Message::Leave(peer.username.clone());
+                state.broadcast_room(&previous, addr, Arc::new(msg)).await;
+
+                let msg = Message::Join(peer.username.clone());
+                state.broadcast_room(&peer.room, addr, Arc::new(msg)).await;
+
+                let msg = Message::System(format!("You joined room {}", peer.room));
+                state.send_to(addr, msg);
+            }
+            Err(err) => state.send_to(addr, Message::System(err.to_string())),
+        },
+        // 列出房间 /rooms 只回复给自己
+        "rooms" => {
+            let rooms = state
+                .room_list()
+                .into_iter()
+                .map(|(name, members)| format!("{} ({})", name, members))
+                .collect::<Vec<_>>()
+                .join(", ");
+            state.send_to(addr, Message::System(format!("Rooms: {}", rooms)));
+        }
+        // 屏蔽用户 /mute <username> /unmute <username>
+        "mute" | "unmute" if !arg.is_empty() => {
+            let text = if arg == peer.username {
+                "You cannot mute yourself".to_string()
+            } else if state.set_muted(addr, arg, name == "mute") {
+                format!("You {}d {}", name, arg)
+            } else {
+                format!("{} is already {}d", arg, name)
+            };
+            state.send_to(addr, Message::System(text));
+        }
+        // 查看本次会话的统计 /stats
+        // Traffic以服务端的视角计数 服务端收到的就是用户发出的
+        "stats" => {
+            let traffic = &peer.traffic;
+            let text = format!(
+                "Session uptime {}s, messages sent {}, messages received {}",
+                peer.joined_at.elapsed().as_secs(),
+                traffic.messages_received.load(Ordering::Relaxed),
+                traffic.messages_sent.load(Ordering::Relaxed),
+            );
+            state.send_to(addr, Message::System(text));
+        }
+        // 确认收到的消息 /ack <seq> 成功时不回复 避免产生新的待确认消息
+        "ack" if !arg.is_empty() => {
+            let text = if !state.config.ack_mode {
+                "Ack mode is disabled".to_string()
+            } else {
+                match arg.parse() {
+                    Ok(seq) if peer.acks.ack(seq) => return,
+                    _ => format!("Invalid sequence number: {}", arg),
+                }
+            };
+            state.send_to(addr, Message::System(text));
+        }
+        // 编辑或删除自己的消息 /edit <seq> <text> /delete <seq>
+        "edit" | "delete" if !state.config.ack_mode => {
+            let msg = Message::System("Ack mode is disabled".to_string());
+            state.send_to(addr, msg);
+        }
+        "edit" | "delete" if !arg.is_empty() => {
+            let (seq, content) = arg
+                .split_once(' ')
+                .map_or((arg, ""), |(seq, content)| (seq, content.trim()));
+            let Ok(seq) = seq.parse() else {
+                let msg = Message::System(format!("Invalid sequence number: {}", seq));
+                state.send_to(addr, msg);
+                return;
+            };
+            let msg = match name {
+                "edit" if content.is_empty() => {
+                    let msg = Message::System("Usage: /edit <seq> <text>".to_string());
+                    state.send_to(addr, msg);
+                    return;
+                }
+                "edit" => Message::Edit {
+                    seq,
+                    content: content.to_string(),
+                },
+                _ => Message::Delete { seq },
+            };
+            match state.check_author(seq, &peer.username, name == "delete") {
+                Ok(room) => state.broadcast_room(&room, addr, Arc::new(msg)).await,
+                Err(err) => state.send_to(addr, Message::System(err.to_string())),
+            }
+        }
+        // 管理命令 需要登录时通过管理员令牌的校验
+        "kick" | "ban" if !peer.admin => {
+            let msg = Message::System("Permission denied".to_string());
+            state.send_to(addr, msg);
+        }
+        // 踢出用户 /kick <username>
+        "kick" if !arg.is_empty() => {
+            if arg == peer.username {
+                let msg = Message::System("You cannot kick yourself".to_string());
+                state.send_to(addr, msg);
+                return;
+            }
+            let text = match state.kick_user(arg) {
+                Some(room) => {
+                    let msg = Message::System(format!("{} was kicked by an admin", arg));
+                    state.broadcast_room(&room, addr, Arc::new(msg)).await;
+                    format!("You kicked {}", arg)
+                }
+                None => format!("User {} is not online", arg),
+            };
+            state.send_to(addr, Message::System(text));
+        }
+        // 封禁IP /ban <ip>
+        "ban" if !arg.is_empty() => {
+            let text = match arg.parse::<IpAddr>() {
+                Ok(ip) if ip == addr.ip() => "You cannot ban yourself".to_string(),
+                Ok(ip) => {
+                    let kicked = state.ban(ip);
+                    format!("Banned {}, {} connections closed", ip, kicked)
+                }
+                Err(_) => format!("Invalid IP address: {}", arg),
+            };
+            state.send_to(addr, Message::System(text));
+        }
+        _ => {
+            let msg = Message::System(format!("Unknown command: /{}", command));
+            state.send_to(addr, msg);
+        }
+    }
+}
+
+/// 不在白名单内的请求
+async fn not_found() -> StatusCode {
+    StatusCode::NOT_FOUND
+}
+
+/// 返回运行状态
+async fn stats_handler(AxumState(state): AxumState<Arc<State>>) -> Json<ChatStats> {
+    Json(state.stats())
+}
+
+/// 输出死信 便于排查投递失败的原因
+async fn log_dead_letters(mut receiver: Receiver<DeadLetter>) {
+    while let Some(letter) = receiver.recv().await {
+        tracing::warn!(
+            "Dead Letter: {} could not receive \"{}\"",
+            letter.addr,
+            letter.message
+        );
+    }
+}
+
+/// 接收管理端口的连接
+async fn accept_admin(listener: TcpListener, state: Arc<State>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                tracing::warn!("Accept Admin Connection Error: {:?}", err);
+                continue;
+            }
+        };
+        tracing::info!("Accept Admin Connection: {:?}", addr);
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut stream = Framed::new(socket, LinesCodec::new());
+            while let Some(Ok(text)) = stream.next().await {
+                state.announce(text).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    type Client = Framed<DuplexStream, LinesCodec>;
+
+    /// 用内存管道代替TCP连接 可以指定任意的对端地址
+    fn connect(state: &Arc<State>, addr: &str) -> Client {
+        let (client, server) = tokio::io::duplex(1024);
+        let addr = PeerId::Tcp(addr.parse().unwrap());
+        tokio::spawn(handle_connection(server, addr, state.clone()));
+        Framed::new(client, LinesCodec::new())
+    }
+
+    async fn next_line(client: &mut Client) -> String {
+        tokio::time::timeout(Duration::from_secs(1), client.next())
+            .await
+            .expect("line in time")
+            .expect("connection open")
+            .unwrap()
+    }
+
+    async fn login(state: &Arc<State>, addr: &str, username: &str) -> Client {
+        let mut client = connect(state, addr);
+        assert_eq!(next_line(&mut client).await, "Please input your username:");
+        client.send(username).await.unwrap();
+        wait_until(|| state.users.contains_key(username)).await;
+        client
+    }
+
+    /// 等待服务端处理完成
+    async fn wait_until(condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("condition in time");
+    }
+
+    #[tokio::test]
+    async fn announce_reaches_every_room() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+
+        bob.send("/join rust").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob leave the chat");
+        assert_eq!(next_line(&mut bob).await, "*** You joined room rust ***");
+
+        // 系统公告不属于任何房间 所有人都会收到
+        state.announce("maintenance at noon").await;
+        assert_eq!(next_line(&mut alice).await, "*** maintenance at noon ***");
+        assert_eq!(next_line(&mut bob).await, "*** maintenance at noon ***");
+    }
+
+    #[tokio::test]
+    async fn stats_report_peers_and_traffic() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+        bob.send("hi").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob: hi");
+
+        let Json(stats) = stats_handler(AxumState(state.clone())).await;
+        let stats = serde_json::to_value(stats).unwrap();
+        assert_eq!(stats["peers"], 2);
+        assert_eq!(stats["rooms"], 1);
+        let connections = stats["connections"].as_array().unwrap();
+        let bob_stats = connections
+            .iter()
+            .find(|peer| peer["username"] == "bob")
+            .unwrap();
+        assert_eq!(bob_stats["addr"], "10.0.0.2:1");
+        // 用户名不计入 登录后收到一条消息
+        assert_eq!(bob_stats["messages_received"], 1);
+        assert_eq!(bob_stats["bytes_received"], 3);
+    }
+
+    async fn closed(client: &mut Client) -> bool {
+        tokio::time::timeout(Duration::from_secs(1), client.next())
+            .await
+            .expect("close in time")
+            .is_none()
+    }
+
+    #[tokio::test]
+    async fn duplicate_login_rejects_or_replaces() {
+        // 默认拒绝新的连接 要求重新输入用户名
+        let state = Arc::new(State::default());
+        let _alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut second = connect(&state, "10.0.0.2:1");
+        assert_eq!(next_line(&mut second).await, "Please input your username:");
+        second.send("alice").await.unwrap();
+        assert_eq!(
+            next_line(&mut second).await,
+            "Username alice is already taken"
+        );
+        assert_eq!(next_line(&mut second).await, "Please input your username:");
+
+        // 配置为顶替时踢掉旧的连接 不建议旧连接重连
+        let state = Arc::new(State::new(ServerConfig {
+            duplicate_login: DuplicateLogin::Replace,
+            ..ServerConfig::default()
+        }));
+        let mut bob = login(&state, "10.0.0.3:1", "bob").await;
+        let mut old = login(&state, "10.0.0.1:1", "alice").await;
+        assert_eq!(next_line(&mut bob).await, "alice join the chat");
+        let mut new = connect(&state, "10.0.0.2:1");
+        assert_eq!(next_line(&mut new).await, "Please input your username:");
+        new.send("alice").await.unwrap();
+
+        assert_eq!(
+            next_line(&mut old).await,
+            "*** Your session was replaced by a new connection ***"
+        );
+        assert!(closed(&mut old).await);
+        assert_eq!(
+            next_line(&mut bob).await,
+            "*** alice reconnected from a new session ***"
+        );
+        assert_eq!(
+            *state.users.get("alice").unwrap(),
+            PeerId::Tcp("10.0.0.2:1".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn room_limit_and_cleanup() {
+        let state = Arc::new(State::new(ServerConfig {
+            max_rooms: 1,
+            ..ServerConfig::default()
+        }));
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+
+        alice.send("/join rust").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** You joined room rust ***");
+        assert_eq!(next_line(&mut bob).await, "alice leave the chat");
+        // 默认房间不计入上限 已存在的房间可以直接加入
+        bob.send("/join go").await.unwrap();
+        assert_eq!(
+            next_line(&mut bob).await,
+            "*** Room limit reached, cannot create room go ***"
+        );
+        bob.send("/join rust").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+        assert_eq!(next_line(&mut bob).await, "*** You joined room rust ***");
+        assert_eq!(state.room_list(), [("rust".to_string(), 2)]);
+
+        // 最后一个成员离开后房间被移除 名额可以再次使用
+        alice.send("/join lobby").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** You joined room lobby ***");
+        drop(bob);
+        wait_until(|| state.room_list() == [("lobby".to_string(), 1)]).await;
+        alice.send("/join go").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** You joined room go ***");
+    }
+
+    #[test]
+    fn username_validation_rules() {
+        let config = ServerConfig {
+            max_username_len: 5,
+            ..ServerConfig::default()
+        };
+        assert_eq!(config.validate_username("  alice \t").unwrap(), "alice");
+        // 按字符计数 不按字节
+        assert_eq!(
+            config.validate_username("张三李四王").unwrap(),
+            "张三李四王"
+        );
+        assert_eq!(
+            config.validate_username("   ").unwrap_err(),
+            "Username cannot be empty"
+        );
+        assert_eq!(
+            config.validate_username("alice2").unwrap_err(),
+            "Username cannot be longer than 5 characters"
+        );
+        assert_eq!(
+            config.validate_username("a\u{7}b").unwrap_err(),
+            "Username cannot contain control characters"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_username_is_asked_again() {
+        let state = Arc::new(State::default());
+        let mut client = connect(&state, "10.0.0.1:1");
+        assert_eq!(next_line(&mut client).await, "Please input your username:");
+        client.send(" ").await.unwrap();
+        assert_eq!(next_line(&mut client).await, "Username cannot be empty");
+        assert_eq!(next_line(&mut client).await, "Please input your username:");
+        client.send(" alice ").await.unwrap();
+        wait_until(|| state.users.contains_key("alice")).await;
+    }
+
+    #[tokio::test]
+    async fn room_rate_limit_drops_floods() {
+        let state = Arc::new(State::new(ServerConfig {
+            room_rate: 20.0,
+            room_burst: 2.0,
+            ..ServerConfig::default()
+        }));
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+
+        // 令牌用完后丢弃消息 只在刚进入限流时通知一次
+        for text in ["1", "2", "3", "4"] {
+            bob.send(text).await.unwrap();
+        }
+        assert_eq!(next_line(&mut alice).await, "bob: 1");
+        assert_eq!(next_line(&mut alice).await, "bob: 2");
+        assert_eq!(next_line(&mut alice).await, "*** Room is rate limited ***");
+        assert_eq!(next_line(&mut bob).await, "*** Room is rate limited ***");
+
+        // 补充令牌后恢复
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        bob.send("5").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob: 5");
+    }
+
+    #[test]
+    fn room_rate_is_per_room() {
+        let state = State::new(ServerConfig {
+            room_rate: 1.0,
+            room_burst: 1.0,
+            ..ServerConfig::default()
+        });
+        {
+            let mut rooms = state.rooms.lock().unwrap();
+            state.occupy_room(&mut rooms, "a");
+            state.occupy_room(&mut rooms, "b");
+        }
+        assert_eq!(state.check_room_rate("a"), RoomRate::Allowed);
+        assert_eq!(
+            state.check_room_rate("a"),
+            RoomRate::Limited { first: true }
+        );
+        assert_eq!(
+            state.check_room_rate("a"),
+            RoomRate::Limited { first: false }
+        );
+        assert_eq!(state.check_room_rate("b"), RoomRate::Allowed);
+        // 不存在的房间不限流
+        assert_eq!(state.check_room_rate("missing"), RoomRate::Allowed);
+    }
+
+    /// 读取剩余的所有行直到连接关闭
+    async fn remaining_lines(client: &mut Client) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), client.next())
+                .await
+                .expect("line or close in time")
+            {
+                Some(line) => lines.push(line.unwrap()),
+                None => return lines,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_peer_is_evicted_after_repeated_overflows() {
+        let state = Arc::new(State::new(ServerConfig {
+            peer_backlog: 2,
+            overflow_limit: 2,
+            ..ServerConfig::default()
+        }));
+        // alice不读取 管道和积压都满后 广播给她的消息开始溢出
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        let padding = "x".repeat(200);
+        for i in 0..15 {
+            bob.send(format!("{} {}", i, padding)).await.unwrap();
+        }
+        wait_until(|| !state.users.contains_key("alice")).await;
+        assert!(state.users.contains_key("bob"));
+
+        // 积压中保留了踢出提示的位置
+        let lines = remaining_lines(&mut alice).await;
+        assert_eq!(
+            lines[lines.len() - 2..],
+            ["*** too slow ***", "RECONNECT_AFTER 10 too slow"]
+        );
+        assert!(lines.len() < 15);
+    }
+
+    #[tokio::test]
+    async fn connections_are_limited_per_ip() {
+        let state = Arc::new(State::new(ServerConfig {
+            max_conn_per_ip: 1,
+            ..ServerConfig::default()
+        }));
+        let first = login(&state, "10.0.0.1:1", "alice").await;
+        // 其他IP不受影响
+        let _bob = login(&state, "10.0.0.2:1", "bob").await;
+
+        let mut second = connect(&state, "10.0.0.1:2");
+        assert_eq!(
+            remaining_lines(&mut second).await,
+            [
+                "Too many connections from your address",
+                "RECONNECT_AFTER 30 Too many connections from your address",
+            ]
+        );
+
+        // 断开后归还名额
+        drop(first);
+        wait_until(|| !state.users.contains_key("alice")).await;
+        wait_until(|| {
+            !state
+                .conns_per_ip
+                .contains_key(&"10.0.0.1".parse().unwrap())
+        })
+        .await;
+        let _third = login(&state, "10.0.0.1:3", "carol").await;
+    }
+
+    #[tokio::test]
+    async fn muted_users_are_not_delivered() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+        let mut carol = login(&state, "10.0.0.3:1", "carol").await;
+        assert_eq!(next_line(&mut alice).await, "carol join the chat");
+        assert_eq!(next_line(&mut bob).await, "carol join the chat");
+
+        alice.send("/mute alice").await.unwrap();
+        assert_eq!(
+            next_line(&mut alice).await,
+            "*** You cannot mute yourself ***"
+        );
+        alice.send("/mute bob").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** You muted bob ***");
+        alice.send("/mute bob").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** bob is already muted ***");
+
+        // 只屏蔽聊天消息 其他人照常收到
+        bob.send("hello").await.unwrap();
+        assert_eq!(next_line(&mut carol).await, "bob: hello");
+        carol.send("hi").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "carol: hi");
+
+        alice.send("/unmute bob").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** You unmuted bob ***");
+        bob.send("again").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob: again");
+    }
+
+    #[tokio::test]
+    async fn admin_can_kick_and_ban() {
+        let state = Arc::new(State::new(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        }));
+        // 输入用户名前先发送管理员令牌
+        let mut alice = connect(&state, "10.0.0.1:1");
+        assert_eq!(next_line(&mut alice).await, "Please input your username:");
+        alice.send("/admin wrong").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "Invalid admin token");
+        assert_eq!(next_line(&mut alice).await, "Please input your username:");
+        alice.send("/admin secret").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "Admin access granted");
+        assert_eq!(next_line(&mut alice).await, "Please input your username:");
+        alice.send("alice").await.unwrap();
+        wait_until(|| state.users.contains_key("alice")).await;
+
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        let mut carol = login(&state, "10.0.0.3:1", "carol").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+        assert_eq!(next_line(&mut alice).await, "carol join the chat");
+        assert_eq!(next_line(&mut bob).await, "carol join the chat");
+
+        bob.send("/kick alice").await.unwrap();
+        assert_eq!(next_line(&mut bob).await, "*** Permission denied ***");
+
+        alice.send("/kick bob").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** You kicked bob ***");
+        assert_eq!(
+            remaining_lines(&mut bob).await,
+            [
+                "*** You were kicked by an admin ***",
+                "RECONNECT_AFTER 60 You were kicked by an admin",
+            ]
+        );
+        assert_eq!(
+            next_line(&mut carol).await,
+            "*** bob was kicked by an admin ***"
+        );
+
+        alice.send("/ban 10.0.0.1").await.unwrap();
+        assert_eq!(
+            next_line(&mut alice).await,
+            "*** You cannot ban yourself ***"
+        );
+        alice.send("/ban 10.0.0.3").await.unwrap();
+        assert_eq!(
+            next_line(&mut alice).await,
+            "*** Banned 10.0.0.3, 1 connections closed ***"
+        );
+        assert_eq!(
+            remaining_lines(&mut carol).await,
+            [
+                "*** You were banned by an admin ***",
+                "RECONNECT_AFTER 3600 You were banned by an admin",
+            ]
+        );
+
+        // 封禁后的新连接直接被拒绝
+        let mut again = connect(&state, "10.0.0.3:2");
+        assert_eq!(
+            remaining_lines(&mut again).await,
+            [
+                "You are banned from this server",
+                "RECONNECT_AFTER 3600 You were banned by an admin",
+            ]
+        );
+    }
+
+    #[test]
+    fn eviction_advisories() {
+        let advisories = [
+            (Eviction::Replaced, None),
+            (
+                Eviction::Shutdown,
+                Some("RECONNECT_AFTER 30 Server is shutting down"),
+            ),
+            (Eviction::TooSlow, Some("RECONNECT_AFTER 10 too slow")),
+            (
+                Eviction::Kicked,
+                Some("RECONNECT_AFTER 60 You were kicked by an admin"),
+            ),
+            (
+                Eviction::Banned,
+                Some("RECONNECT_AFTER 3600 You were banned by an admin"),
+            ),
+            (
+                Eviction::TooManyConnections,
+                Some("RECONNECT_AFTER 30 Too many connections from your address"),
+            ),
+        ];
+        // 被顶替时不建议重连 否则新旧连接会互相踢下线
+        for (eviction, expected) in advisories {
+            assert_eq!(eviction.advisory().as_deref(), expected, "{:?}", eviction);
+        }
+    }
+
+    fn ack_state() -> Arc<State> {
+        Arc::new(State::new(ServerConfig {
+            ack_mode: true,
+            ..ServerConfig::default()
+        }))
+    }
+
+    #[test]
+    fn acks_track_lag() {
+        let acks = Acks::default();
+        assert_eq!((acks.next(), acks.next(), acks.next()), (1, 2, 3));
+        assert_eq!(acks.lag(), 3);
+        // 不能确认还没有发出的序号 确认较小的序号不会回退
+        assert!(!acks.ack(4));
+        assert!(acks.ack(2));
+        assert!(acks.ack(1));
+        assert_eq!(acks.lag(), 1);
+        assert!(acks.ack(3));
+        assert_eq!(acks.lag(), 0);
+    }
+
+    #[tokio::test]
+    async fn ack_mode_numbers_lines_per_connection() {
+        let state = ack_state();
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "1 bob join the chat");
+
+        bob.send("hi").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "2 #1 bob: hi");
+        assert_eq!(next_line(&mut bob).await, "1 *** Sent #1 ***");
+
+        // 确认成功时不回复 无效的序号会收到提示
+        alice.send("/ack 9").await.unwrap();
+        assert_eq!(
+            next_line(&mut alice).await,
+            "3 *** Invalid sequence number: 9 ***"
+        );
+        alice.send("/ack 3").await.unwrap();
+        wait_until(|| {
+            let stats = state.stats();
+            stats
+                .connections
+                .iter()
+                .any(|peer| peer.username == "alice" && peer.unacked == 0)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn authors_can_edit_and_delete_their_messages() {
+        let state = ack_state();
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "1 bob join the chat");
+        bob.send("hi").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "2 #1 bob: hi");
+        assert_eq!(next_line(&mut bob).await, "1 *** Sent #1 ***");
+
+        alice.send("/edit 1 hacked").await.unwrap();
+        assert_eq!(
+            next_line(&mut alice).await,
+            "3 *** You can only change your own messages ***"
+        );
+        bob.send("/edit 1 hello").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "4 #1 (edited) hello");
+        bob.send("/delete 1").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "5 #1 (deleted)");
+
+        // 删除后不能再修改
+        bob.send("/delete 1").await.unwrap();
+        assert_eq!(next_line(&mut bob).await, "2 *** Message 1 not found ***");
+        bob.send("/edit x y").await.unwrap();
+        assert_eq!(
+            next_line(&mut bob).await,
+            "3 *** Invalid sequence number: x ***"
+        );
+        bob.send("/edit 1").await.unwrap();
+        assert_eq!(
+            next_line(&mut bob).await,
+            "4 *** Usage: /edit <seq> <text> ***"
+        );
+    }
+
+    #[tokio::test]
+    async fn edit_requires_ack_mode() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        alice.send("/delete 1").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "*** Ack mode is disabled ***");
+    }
+
+    #[tokio::test]
+    async fn rooms_command_lists_member_counts() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+        bob.send("/join rust").await.unwrap();
+        assert_eq!(next_line(&mut bob).await, "*** You joined room rust ***");
+        let _carol = login(&state, "10.0.0.3:1", "carol").await;
+
+        // 按房间名排序 只回复给自己
+        alice.send("/rooms").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob leave the chat");
+        assert_eq!(next_line(&mut alice).await, "carol join the chat");
+        assert_eq!(
+            next_line(&mut alice).await,
+            "*** Rooms: lobby (2), rust (1) ***"
+        );
+    }
+
+    #[tokio::test]
+    async fn private_replies_keep_the_kick_reservation() {
+        let state = Arc::new(State::new(ServerConfig {
+            peer_backlog: 2,
+            ..ServerConfig::default()
+        }));
+        // alice不读取 私信不等待空位 积压满后直接丢弃
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let addr = PeerId::Tcp("10.0.0.1:1".parse().unwrap());
+        let padding = "x".repeat(200);
+        for i in 0..20 {
+            state.send_to(addr, Message::System(format!("{} {}", i, padding)));
+        }
+
+        // 预留的位置仍然可以放下踢出提示
+        assert!(state.kick_user("alice").is_some());
+        let lines = remaining_lines(&mut alice).await;
+        assert!(lines.len() < 20);
+        assert_eq!(
+            lines[lines.len() - 2..],
+            [
+                "*** You were kicked by an admin ***",
+                "RECONNECT_AFTER 60 You were kicked by an admin",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unix_peers_have_distinct_ids() {
+        let state = Arc::new(State::new(ServerConfig {
+            max_conn_per_ip: 1,
+            ..ServerConfig::default()
+        }));
+        let mut clients = Vec::new();
+        for (id, username) in [(1, "alice"), (2, "bob")] {
+            let (client, server) = tokio::io::duplex(1024);
+            tokio::spawn(handle_connection(server, PeerId::Unix(id), state.clone()));
+            let mut client = Framed::new(client, LinesCodec::new());
+            assert_eq!(next_line(&mut client).await, "Please input your username:");
+            client.send(username).await.unwrap();
+            clients.push(client);
+        }
+        // Unix Socket不受按IP的连接数限制
+        wait_until(|| state.users.len() == 2).await;
+        assert_eq!(*state.users.get("bob").unwrap(), PeerId::Unix(2));
+        assert_eq!(PeerId::Unix(2).to_string(), "unix:2");
+        assert!(PeerId::Unix(2).ip().is_unspecified());
+
+        let stats = serde_json::to_value(state.stats()).unwrap();
+        let mut addrs: Vec<_> = stats["connections"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|peer| peer["addr"].as_str().unwrap().to_string())
+            .collect();
+        addrs.sort();
+        assert_eq!(addrs, ["unix:1", "unix:2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_force_closes_after_grace() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+
+        let start = tokio::time::Instant::now();
+        let clients = async {
+            // bob收到通知后主动断开 alice一直不断开
+            let notice = "*** Server is shutting down in 10 seconds ***";
+            assert_eq!(next_line(&mut bob).await, notice);
+            drop(bob);
+            assert_eq!(next_line(&mut alice).await, notice);
+            assert_eq!(next_line(&mut alice).await, "bob leave the chat");
+            alice
+        };
+        let ((), mut alice) = tokio::join!(shutdown(&state), clients);
+
+        // 超过宽限时间后 剩下的alice被强制断开 收到原因和重连建议
+        assert!(start.elapsed() >= Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS));
+        assert!(state.map.is_empty());
+        assert_eq!(
+            remaining_lines(&mut alice).await,
+            [
+                "*** Server is shutting down ***",
+                "RECONNECT_AFTER 30 Server is shutting down"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn undeliverable_messages_go_to_dead_letters() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let state = Arc::new(
+            State::new(ServerConfig {
+                peer_backlog: 2,
+                overflow_limit: 100,
+                ..ServerConfig::default()
+            })
+            .with_dead_letters(tx),
+        );
+        // alice不读取 积压满后广播给她的消息进入死信
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        let padding = "x".repeat(200);
+        for i in 0..15 {
+            bob.send(format!("{} {}", i, padding)).await.unwrap();
+        }
+
+        // 每条消息要么送达 要么进入死信
+        let mut delivered = 0;
+        let mut dead = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while delivered + dead.len() < 15 {
+                while let Ok(letter) = rx.try_recv() {
+                    dead.push(letter);
+                }
+                let line = tokio::time::timeout(Duration::from_millis(10), alice.next()).await;
+                if let Ok(Some(line)) = line {
+                    delivered += line.unwrap().ends_with(&padding) as usize;
+                }
+            }
+        })
+        .await
+        .expect("every message accounted for");
+
+        assert!(!dead.is_empty());
+        let alice_addr = PeerId::Tcp("10.0.0.1:1".parse().unwrap());
+        for letter in &dead {
+            assert_eq!(letter.addr, alice_addr);
+            assert_eq!(letter.message.sender(), Some("bob"));
+        }
+        assert!(state.users.contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn stats_app_only_serves_get() {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt as _;
+
+        let state = Arc::new(State::default());
+        let _alice = login(&state, "10.0.0.1:1", "alice").await;
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = stats_app(state.clone())
+            .oneshot(request("GET", "/stats"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["peers"], 1);
+
+        // 其他方法和路径都是404 不返回405
+        for (method, uri) in [("POST", "/stats"), ("DELETE", "/livez"), ("GET", "/admin")] {
+            let response = stats_app(state.clone())
+                .oneshot(request(method, uri))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_FOUND,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+        let response = stats_app(state)
+            .oneshot(request("GET", "/livez"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn stats_command_reports_session_counts() {
+        let state = Arc::new(State::default());
+        let mut alice = login(&state, "10.0.0.1:1", "alice").await;
+        let mut bob = login(&state, "10.0.0.2:1", "bob").await;
+        bob.send("hi").await.unwrap();
+        bob.send("there").await.unwrap();
+        assert_eq!(next_line(&mut alice).await, "bob join the chat");
+        assert_eq!(next_line(&mut alice).await, "bob: hi");
+        assert_eq!(next_line(&mut alice).await, "bob: there");
+        alice.send("hello").await.unwrap();
+        assert_eq!(next_line(&mut bob).await, "alice: hello");
+
+        // 以用户的视角计数 发出的包括/stats本身 用户名不计入
+        alice.send("/stats").await.unwrap();
+        let line = next_line(&mut alice).await;
+        assert!(line.starts_with("*** Session uptime "), "{}", line);
+        assert!(
+            line.ends_with("s, messages sent 2, messages received 3 ***"),
+            "{}",
+            line
+        );
+    }
+
+    /// 收集日志输出的Writer
+    #[derive(Debug, Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+
+        fn line(&self, pattern: &str) -> String {
+            self.text()
+                .lines()
+                .find(|line| line.contains(pattern))
+                .unwrap_or_else(|| panic!("no log line contains {}", pattern))
+                .to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_span_carries_addr_and_username() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = Arc::new(State::default());
+        let (client, server) = tokio::io::duplex(1024);
+        spawn_connection(
+            server,
+            PeerId::Tcp("10.0.0.1:1".parse().unwrap()),
+            state.clone(),
+        );
+        let mut alice = Framed::new(client, LinesCodec::new());
+        assert_eq!(next_line(&mut alice).await, "Please input your username:");
+        alice.send("alice").await.unwrap();
+        alice.send("hi").await.unwrap();
+        wait_until(|| logs.text().contains("Receive Message: hi")).await;
+
+        // 登录前只有addr 登录后同一个span带上username
+        let line = logs.line("Accept Connection");
+        assert!(line.contains("conn{addr=10.0.0.1:1}"), "{}", line);
+        let line = logs.line("Receive Message: hi");
+        assert!(
+            line.contains("conn{addr=10.0.0.1:1 username=\"alice\"}"),
+            "{}",
+            line
+        );
+    }
+}
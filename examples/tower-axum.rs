@@ -1,13 +1,30 @@
 use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
     fmt,
     future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
     pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     task::{ready, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::MatchedPath,
+    http::{header, uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router, ServiceExt,
+};
+use dashmap::DashMap;
+use futures_util::StreamExt as _;
 use pin_project::pin_project;
+use serde::Serialize;
 use tokio::net::TcpListener;
 use tower::{Layer as TowerLayer, Service};
 use tracing::level_filters::LevelFilter;
@@ -24,23 +41,34 @@ use tracing_subscriber::{
 #[derive(Debug, Clone)]
 pub struct MyLogService<S> {
     inner: S,
+    // 流式响应只记录状态和Header
+    summarize_streaming: bool,
+    // 记录响应体的前多少字节 为0时只记录状态和Header
+    body_preview: usize,
 }
 
 impl<S> MyLogService<S> {
     pub fn new(inner: S) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            summarize_streaming: true,
+            body_preview: 0,
+        }
     }
 }
 
 impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for MyLogService<S>
 where
     S: Service<axum::http::Request<ReqBody>, Response = axum::response::Response<ResBody>>,
-    ResBody: Default + fmt::Debug,
+    S::Future: Send + 'static,
+    S::Error: Send,
+    ResBody: HttpBody<Data = Bytes> + Default + fmt::Debug + Send + 'static,
+    ResBody::Error: Into<tower::BoxError>,
     ReqBody: fmt::Debug,
 {
-    type Response = S::Response;
+    type Response = axum::response::Response<Body>;
     type Error = S::Error;
-    type Future = ResponseFuture<S::Future>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(
         &mut self,
@@ -54,23 +82,99 @@ where
         tracing::info!("Request: {:?}", req);
 
         // 封装一个Future，在这里处理Response
-        ResponseFuture {
+        let future = ResponseFuture {
             response_future: self.inner.call(req),
-        }
+            summarize_streaming: self.summarize_streaming,
+        };
+        let body_preview = self.body_preview;
+
+        Box::pin(async move {
+            let response = future.await?;
+            // 流式响应和二进制内容不预览Body
+            if body_preview == 0
+                || response.body().size_hint().exact().is_none()
+                || !is_text_content(response.headers())
+            {
+                return Ok(response.map(Body::new));
+            }
+
+            // 只读取预览需要的前几块数据 剩余部分不缓冲 原样接在后面发送
+            let (mut parts, body) = response.into_parts();
+            let total = body.size_hint().exact().unwrap_or_default();
+            let mut rest = Body::new(body).into_data_stream();
+            let mut head = Vec::new();
+            let mut head_len = 0;
+            while head_len < body_preview {
+                match rest.next().await {
+                    Some(Ok(chunk)) => {
+                        head_len += chunk.len();
+                        head.push(chunk);
+                    }
+                    Some(Err(err)) => {
+                        tracing::warn!("Log read body error: {}", err);
+                        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                    }
+                    None => break,
+                }
+            }
+            let preview: Vec<u8> = head
+                .iter()
+                .flat_map(|chunk| chunk.iter().copied())
+                .take(body_preview)
+                .collect();
+            tracing::info!(
+                "Response Body ({} of {} bytes): {}",
+                preview.len(),
+                total,
+                String::from_utf8_lossy(&preview)
+            );
+
+            // 重新拼接后长度信息丢失 显式设置Content-Length 避免变成分块传输
+            parts
+                .headers
+                .entry(header::CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(total));
+            let head = futures_util::stream::iter(head.into_iter().map(Ok));
+            let body = Body::from_stream(head.chain(rest));
+            Ok(axum::http::Response::from_parts(parts, body))
+        })
     }
 }
 
+/// 根据Content-Type判断是否为文本内容 未知类型视为二进制
+fn is_text_content(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("json")
+        || mime.ends_with("xml")
+        || mime == "application/javascript"
+        || mime == "application/x-www-form-urlencoded"
+}
+
 #[pin_project]
 #[derive(Debug)]
 pub struct ResponseFuture<F> {
     #[pin]
     response_future: F,
+    summarize_streaming: bool,
 }
 
 impl<F, B, E> Future for ResponseFuture<F>
 where
     F: Future<Output = Result<axum::response::Response<B>, E>>,
-    B: Default + fmt::Debug,
+    B: HttpBody + Default + fmt::Debug,
 {
     type Output = F::Output;
 
@@ -82,46 +186,2026 @@ where
         let this = self.project();
         // 运行Future
         let response = ready!(this.response_future.poll(cx))?;
-        tracing::info!("Response: {:?}", response);
+        // 长度未知的Body视为流式响应 无法也不应该打印Body
+        if *this.summarize_streaming && response.body().size_hint().exact().is_none() {
+            tracing::info!(
+                "Response: status={} headers={:?} (streaming body)",
+                response.status(),
+                response.headers()
+            );
+        } else {
+            tracing::info!("Response: {:?}", response);
+        }
         Poll::Ready(Ok(response))
     }
 }
 
 // 包装成Layer
 #[derive(Debug, Clone)]
-pub struct MyLogLayer;
+pub struct MyLogLayer {
+    summarize_streaming: bool,
+    body_preview: usize,
+}
+
+impl MyLogLayer {
+    pub fn new() -> Self {
+        Self {
+            summarize_streaming: true,
+            body_preview: 0,
+        }
+    }
+
+    /// 记录文本响应体的前len个字节 需要缓冲长度已知的Body
+    pub fn body_preview(mut self, len: usize) -> Self {
+        self.body_preview = len;
+        self
+    }
+
+    /// 是否对流式响应只记录状态和Header
+    pub fn summarize_streaming(mut self, enabled: bool) -> Self {
+        self.summarize_streaming = enabled;
+        self
+    }
+}
+
+impl Default for MyLogLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<S> TowerLayer<S> for MyLogLayer {
     type Service = MyLogService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        MyLogService::new(inner)
+        MyLogService {
+            inner,
+            summarize_streaming: self.summarize_streaming,
+            body_preview: self.body_preview,
+        }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Tracing
-    let console_layer = ConsoleLayer::new()
-        .with_span_events(FmtSpan::CLOSE)
-        .pretty()
-        .with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(console_layer).init();
+/// 为每个响应追加安全相关的Header
+#[derive(Debug, Clone)]
+pub struct SecurityHeadersService<S> {
+    inner: S,
+    headers: Arc<HeaderMap>,
+}
 
-    let addr = "0.0.0.0:3000";
+impl<S> SecurityHeadersService<S> {
+    pub fn new(inner: S, headers: Arc<HeaderMap>) -> Self {
+        Self { inner, headers }
+    }
+}
 
-    let tower_log_layer = MyLogLayer;
-    let app = Router::new()
-        .route("/", get(index_handler))
-        .layer(tower_log_layer);
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for SecurityHeadersService<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::response::Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = SecurityHeadersFuture<S::Future>;
 
-    let listener = TcpListener::bind(addr).await?;
-    tracing::info!("Listening on: {}", addr);
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
 
-    axum::serve(listener, app.into_make_service()).await?;
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        SecurityHeadersFuture {
+            response_future: self.inner.call(req),
+            headers: self.headers.clone(),
+        }
+    }
+}
 
-    Ok(())
+#[pin_project]
+#[derive(Debug)]
+pub struct SecurityHeadersFuture<F> {
+    #[pin]
+    response_future: F,
+    headers: Arc<HeaderMap>,
 }
 
-async fn index_handler() -> Result<&'static str, impl IntoResponse> {
-    Err((StatusCode::INTERNAL_SERVER_ERROR).into_response())
+impl<F, B, E> Future for SecurityHeadersFuture<F>
+where
+    F: Future<Output = Result<axum::response::Response<B>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let this = self.project();
+        let mut response = ready!(this.response_future.poll(cx))?;
+        // 只补充缺失的Header 不覆盖Handler自己设置的值
+        for (name, value) in this.headers.iter() {
+            response
+                .headers_mut()
+                .entry(name)
+                .or_insert_with(|| value.clone());
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityHeadersLayer {
+    headers: HeaderMap,
+}
+
+impl SecurityHeadersLayer {
+    /// 默认包含 nosniff 和 DENY，CSP由调用方指定
+    pub fn new(content_security_policy: HeaderValue) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        headers.insert(header::CONTENT_SECURITY_POLICY, content_security_policy);
+        Self { headers }
+    }
+
+    /// 追加或替换一个Header
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+impl<S> TowerLayer<S> for SecurityHeadersLayer {
+    type Service = SecurityHeadersService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SecurityHeadersService::new(inner, Arc::new(self.headers.clone()))
+    }
+}
+
+/// 在响应中追加Server-Timing 浏览器开发者工具可以直接展示后端耗时
+#[derive(Debug, Clone)]
+pub struct ServerTiming<S> {
+    inner: S,
+}
+
+impl<S> ServerTiming<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for ServerTiming<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::response::Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ServerTimingFuture<S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        ServerTimingFuture {
+            started_at: Instant::now(),
+            response_future: self.inner.call(req),
+        }
+    }
+}
+
+#[pin_project]
+#[derive(Debug)]
+pub struct ServerTimingFuture<F> {
+    #[pin]
+    response_future: F,
+    started_at: Instant,
+}
+
+impl<F, B, E> Future for ServerTimingFuture<F>
+where
+    F: Future<Output = Result<axum::response::Response<B>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let this = self.project();
+        let mut response = ready!(this.response_future.poll(cx))?;
+        // 只统计到响应头产生为止 不包含Body的传输时间
+        let duration = this.started_at.elapsed().as_secs_f64() * 1000.0;
+        let value = HeaderValue::from_str(&format!("app;dur={:.3}", duration))
+            .expect("server timing is a valid header value");
+        // Server-Timing可以有多条 追加而不是覆盖
+        response
+            .headers_mut()
+            .append(HeaderName::from_static("server-timing"), value);
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerTimingLayer;
+
+impl<S> TowerLayer<S> for ServerTimingLayer {
+    type Service = ServerTiming<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ServerTiming::new(inner)
+    }
+}
+
+/// 限制请求体大小 超出时直接返回413 不调用内部Service
+#[derive(Debug, Clone)]
+pub struct BodyLimit<S> {
+    inner: S,
+    max: u64,
+}
+
+impl<S> BodyLimit<S> {
+    pub fn new(inner: S, max: u64) -> Self {
+        Self { inner, max }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for BodyLimit<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::response::Response<ResBody>>,
+    ReqBody: HttpBody,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BodyLimitFuture<S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        // 优先使用Content-Length 没有时使用Body的长度上限
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .or_else(|| req.body().size_hint().upper());
+
+        match content_length {
+            Some(length) if length <= self.max => BodyLimitFuture::Inner {
+                response_future: self.inner.call(req),
+            },
+            Some(_) => BodyLimitFuture::Rejected {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+            },
+            // 长度未知的流式请求无法提前判断 保守起见要求客户端提供长度
+            None => BodyLimitFuture::Rejected {
+                status: StatusCode::LENGTH_REQUIRED,
+            },
+        }
+    }
+}
+
+#[pin_project(project = BodyLimitFutureProj)]
+#[derive(Debug)]
+pub enum BodyLimitFuture<F> {
+    Inner {
+        #[pin]
+        response_future: F,
+    },
+    Rejected {
+        status: StatusCode,
+    },
+}
+
+impl<F, B, E> Future for BodyLimitFuture<F>
+where
+    F: Future<Output = Result<axum::response::Response<B>, E>>,
+    B: Default,
+{
+    type Output = F::Output;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        match self.project() {
+            BodyLimitFutureProj::Inner { response_future } => response_future.poll(cx),
+            BodyLimitFutureProj::Rejected { status } => {
+                let mut response = axum::response::Response::new(B::default());
+                *response.status_mut() = *status;
+                Poll::Ready(Ok(response))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BodyLimitLayer {
+    max: u64,
+}
+
+impl BodyLimitLayer {
+    pub fn new(max: u64) -> Self {
+        Self { max }
+    }
+}
+
+impl<S> TowerLayer<S> for BodyLimitLayer {
+    type Service = BodyLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BodyLimit::new(inner, self.max)
+    }
+}
+
+/// 没有匹配到路由的请求使用的统计标签 避免任意路径产生无限多的指标
+const UNMATCHED_ROUTE_LABEL: &str = "unmatched";
+
+/// 按匹配的路由统计的请求数 可以在多个Service之间共享
+/// 使用路由模板而不是原始路径 /users/1和/users/2计入同一个/users/:id
+#[derive(Debug, Clone, Default)]
+pub struct RequestCounts(Arc<DashMap<String, AtomicU64>>);
+
+impl RequestCounts {
+    fn increment(&self, path: &str) {
+        // 路径已存在时只需要读锁
+        if let Some(count) = self.0.get(path) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.0
+            .entry(path.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 当前计数的快照 按路径排序
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.0
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// 渲染为文本格式 供metrics接口使用
+    pub fn render(&self) -> String {
+        self.snapshot()
+            .into_iter()
+            .map(|(path, count)| {
+                format!(
+                    "http_requests_total{{path=\"{}\"}} {}\n",
+                    escape_label_value(&path),
+                    count
+                )
+            })
+            .collect()
+    }
+}
+
+/// 按Prometheus文本格式转义标签值中的反斜杠、双引号和换行
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// 记录每个路由的请求数 需要作为Router::layer使用 路由匹配之后才有MatchedPath
+#[derive(Debug, Clone)]
+pub struct RequestCounter<S> {
+    inner: S,
+    counts: RequestCounts,
+}
+
+impl<S> RequestCounter<S> {
+    pub fn new(inner: S, counts: RequestCounts) -> Self {
+        Self { inner, counts }
+    }
+
+    pub fn counts(&self) -> &RequestCounts {
+        &self.counts
+    }
+}
+
+impl<S, ReqBody> Service<axum::http::Request<ReqBody>> for RequestCounter<S>
+where
+    S: Service<axum::http::Request<ReqBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or(UNMATCHED_ROUTE_LABEL, MatchedPath::as_str);
+        self.counts.increment(route);
+        self.inner.call(req)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestCounterLayer {
+    counts: RequestCounts,
+}
+
+impl RequestCounterLayer {
+    pub fn new(counts: RequestCounts) -> Self {
+        Self { counts }
+    }
+}
+
+impl<S> TowerLayer<S> for RequestCounterLayer {
+    type Service = RequestCounter<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestCounter::new(inner, self.counts.clone())
+    }
+}
+
+/// 租户id的Header名称
+const TENANT_HEADER: &str = "x-tenant-id";
+
+/// 租户配额和每个租户当前窗口的计数
+/// 配额在Layer构建时确定 计数在同一个Layer创建的Service之间共享
+#[derive(Debug, Clone)]
+struct TenantQuotaState {
+    default_quota: u64,
+    quotas: HashMap<String, u64>,
+    window: Duration,
+    // 窗口的开始时间和窗口内的请求数
+    windows: Arc<DashMap<String, (Instant, u64)>>,
+    // 上次清理过期窗口的时间
+    last_sweep: Arc<Mutex<Instant>>,
+}
+
+impl TenantQuotaState {
+    /// 计数一次请求 超出配额时返回距离窗口结束的时间
+    fn acquire(&self, tenant: &str) -> Result<(), Duration> {
+        let quota = self
+            .quotas
+            .get(tenant)
+            .copied()
+            .unwrap_or(self.default_quota);
+        let now = Instant::now();
+        self.sweep(now);
+        let mut entry = self.windows.entry(tenant.to_string()).or_insert((now, 0));
+        let (started_at, count) = entry.value_mut();
+
+        // 固定窗口 过期后重新计数
+        if now.duration_since(*started_at) >= self.window {
+            *started_at = now;
+            *count = 0;
+        }
+        if *count >= quota {
+            return Err(self.window - now.duration_since(*started_at));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// 租户id由客户端决定 每个窗口周期清理一次过期的窗口 避免随机的租户id无限占用内存
+    fn sweep(&self, now: Instant) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if now.duration_since(*last_sweep) < self.window {
+                return;
+            }
+            *last_sweep = now;
+        }
+        self.windows
+            .retain(|_, (started_at, _)| now.duration_since(*started_at) < self.window);
+    }
+}
+
+/// 按租户限制窗口内的请求数 超出时返回429和Retry-After 不调用内部Service
+/// 租户id从x-tenant-id读取 没有时所有匿名请求共享一个配额
+#[derive(Debug, Clone)]
+pub struct TenantQuota<S> {
+    inner: S,
+    state: Arc<TenantQuotaState>,
+}
+
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for TenantQuota<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::response::Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TenantQuotaFuture<S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        let tenant = req
+            .headers()
+            .get(TENANT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        match self.state.acquire(tenant) {
+            Ok(()) => TenantQuotaFuture::Inner {
+                response_future: self.inner.call(req),
+            },
+            Err(retry_after) => {
+                tracing::warn!("Tenant {:?} exceeded its quota", tenant);
+                // 向上取整 至少等待1秒
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                TenantQuotaFuture::Rejected {
+                    retry_after: secs.max(1),
+                }
+            }
+        }
+    }
+}
+
+#[pin_project(project = TenantQuotaFutureProj)]
+#[derive(Debug)]
+pub enum TenantQuotaFuture<F> {
+    Inner {
+        #[pin]
+        response_future: F,
+    },
+    Rejected {
+        retry_after: u64,
+    },
+}
+
+impl<F, B, E> Future for TenantQuotaFuture<F>
+where
+    F: Future<Output = Result<axum::response::Response<B>, E>>,
+    B: Default,
+{
+    type Output = F::Output;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        match self.project() {
+            TenantQuotaFutureProj::Inner { response_future } => response_future.poll(cx),
+            TenantQuotaFutureProj::Rejected { retry_after } => {
+                let mut response = axum::response::Response::new(B::default());
+                *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(*retry_after));
+                Poll::Ready(Ok(response))
+            }
+        }
+    }
+}
+
+/// 所有经过这个Layer的Service共享同一组计数
+#[derive(Debug, Clone)]
+pub struct TenantQuotaLayer {
+    state: TenantQuotaState,
+}
+
+impl TenantQuotaLayer {
+    pub fn new(default_quota: u64, window: Duration) -> Self {
+        Self {
+            state: TenantQuotaState {
+                default_quota,
+                quotas: HashMap::new(),
+                window,
+                windows: Arc::new(DashMap::new()),
+                last_sweep: Arc::new(Mutex::new(Instant::now())),
+            },
+        }
+    }
+
+    /// 为指定租户单独设置配额
+    pub fn quota(mut self, tenant: impl Into<String>, quota: u64) -> Self {
+        self.state.quotas.insert(tenant.into(), quota);
+        self
+    }
+}
+
+impl<S> TowerLayer<S> for TenantQuotaLayer {
+    type Service = TenantQuota<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TenantQuota {
+            inner,
+            state: Arc::new(self.state.clone()),
+        }
+    }
+}
+
+/// 按前缀改写请求路径 用于把服务挂载到不同的路径下
+/// 按顺序匹配 只使用第一条匹配的规则 查询参数保持不变
+#[derive(Debug, Clone)]
+pub struct PathRewrite<S> {
+    inner: S,
+    rules: Arc<Vec<(String, String)>>,
+}
+
+impl<S> PathRewrite<S> {
+    pub fn new(inner: S, rules: Arc<Vec<(String, String)>>) -> Self {
+        Self { inner, rules }
+    }
+
+    /// 返回改写后的路径 没有匹配的规则时返回None
+    /// 前缀必须在路径分隔处结束 /api不会匹配/apis
+    fn rewrite(&self, path: &str) -> Option<String> {
+        self.rules.iter().find_map(|(prefix, replacement)| {
+            let rest = path.strip_prefix(prefix.as_str())?;
+            if !rest.is_empty() && !rest.starts_with('/') && !prefix.ends_with('/') {
+                return None;
+            }
+            let path = format!("{}{}", replacement, rest);
+            Some(if path.is_empty() {
+                "/".to_string()
+            } else {
+                path
+            })
+        })
+    }
+}
+
+impl<S, ReqBody> Service<axum::http::Request<ReqBody>> for PathRewrite<S>
+where
+    S: Service<axum::http::Request<ReqBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: axum::http::Request<ReqBody>) -> Self::Future {
+        if let Some(path) = self.rewrite(req.uri().path()) {
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            // 改写后的路径非法时保持原样 交给内部Service处理
+            let mut parts = req.uri().clone().into_parts();
+            match PathAndQuery::try_from(path_and_query) {
+                Ok(path_and_query) => {
+                    parts.path_and_query = Some(path_and_query);
+                    match Uri::from_parts(parts) {
+                        Ok(uri) => {
+                            tracing::info!("Rewrite path {} -> {}", req.uri(), uri);
+                            *req.uri_mut() = uri;
+                        }
+                        Err(err) => tracing::warn!("Rewrite path error: {}", err),
+                    }
+                }
+                Err(err) => tracing::warn!("Rewrite path error: {}", err),
+            }
+        }
+        self.inner.call(req)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathRewriteLayer {
+    rules: Vec<(String, String)>,
+}
+
+impl PathRewriteLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加一条规则 以prefix开头的路径将prefix替换为replacement
+    pub fn rule(mut self, prefix: impl Into<String>, replacement: impl Into<String>) -> Self {
+        self.rules.push((prefix.into(), replacement.into()));
+        self
+    }
+}
+
+impl<S> TowerLayer<S> for PathRewriteLayer {
+    type Service = PathRewrite<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PathRewrite::new(inner, Arc::new(self.rules.clone()))
+    }
+}
+
+/// 计算ETag时最多缓冲的响应体字节数 更大的或长度未知的流式响应直接放行
+const ETAG_MAX_BODY: u64 = 64 * 1024;
+
+/// 304响应需要保留的Header RFC 9110 15.4.5
+const NOT_MODIFIED_HEADERS: [HeaderName; 5] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::EXPIRES,
+    header::VARY,
+];
+
+/// 根据响应体计算ETag 请求的If-None-Match匹配时返回304
+/// 只缓冲长度已知且不超过ETAG_MAX_BODY的响应体 Handler已经设置ETag时直接使用
+#[derive(Debug, Clone)]
+pub struct ETag<S> {
+    inner: S,
+}
+
+impl<S> ETag<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for ETag<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<tower::BoxError>,
+{
+    type Response = axum::http::Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let response = future.await?;
+            // 只处理成功的响应
+            if response.status() != StatusCode::OK {
+                return Ok(response.map(Body::new));
+            }
+
+            let (mut parts, body) = response.into_parts();
+            // Handler已经设置ETag时不需要缓冲响应体
+            if let Some(etag) = parts.headers.get(header::ETAG) {
+                if etag_matches(if_none_match.as_ref(), etag) {
+                    return Ok(not_modified(&parts.headers));
+                }
+                return Ok(axum::http::Response::from_parts(parts, Body::new(body)));
+            }
+
+            // 流式响应长度未知 缓冲会破坏流式输出 过大的响应占用太多内存
+            let buffered = body
+                .size_hint()
+                .exact()
+                .is_some_and(|len| len <= ETAG_MAX_BODY);
+            if !buffered {
+                return Ok(axum::http::Response::from_parts(parts, Body::new(body)));
+            }
+            let bytes = match axum::body::to_bytes(Body::new(body), ETAG_MAX_BODY as usize).await {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    tracing::warn!("ETag read body error: {}", err);
+                    return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                }
+            };
+
+            let mut hasher = DefaultHasher::new();
+            bytes.hash(&mut hasher);
+            let etag = HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish()))
+                .expect("hex etag is a valid header value");
+            let matched = etag_matches(if_none_match.as_ref(), &etag);
+            parts.headers.insert(header::ETAG, etag);
+            if matched {
+                return Ok(not_modified(&parts.headers));
+            }
+
+            Ok(axum::http::Response::from_parts(parts, Body::from(bytes)))
+        })
+    }
+}
+
+/// If-None-Match可能是逗号分隔的多个ETag 按弱比较忽略W/前缀
+fn etag_matches(if_none_match: Option<&HeaderValue>, etag: &HeaderValue) -> bool {
+    let (Some(Ok(if_none_match)), Ok(etag)) =
+        (if_none_match.map(HeaderValue::to_str), etag.to_str())
+    else {
+        return false;
+    };
+    let opaque = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// 304不带响应体 保留ETag和缓存相关的Header
+fn not_modified(headers: &HeaderMap) -> axum::http::Response<Body> {
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    for name in std::iter::once(header::ETAG).chain(NOT_MODIFIED_HEADERS) {
+        for value in headers.get_all(&name) {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ETagLayer;
+
+impl<S> TowerLayer<S> for ETagLayer {
+    type Service = ETag<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ETag::new(inner)
+    }
+}
+
+/// 校验时最多缓冲的响应体字节数 超过时按校验失败处理
+const VALIDATE_MAX_BODY: usize = 64 * 1024;
+
+/// 用校验函数检查JSON响应 不通过时替换为不包含任何细节的500 避免泄露内部数据
+/// 需要缓冲并解析整个响应体 非JSON的响应直接放行 超过VALIDATE_MAX_BODY的JSON响应同样返回500
+#[derive(Debug, Clone)]
+pub struct ResponseValidate<S, V> {
+    inner: S,
+    validator: Arc<V>,
+}
+
+impl<S, V> ResponseValidate<S, V> {
+    pub fn new(inner: S, validator: Arc<V>) -> Self {
+        Self { inner, validator }
+    }
+}
+
+impl<S, V, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for ResponseValidate<S, V>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    V: Fn(&serde_json::Value) -> Result<(), String> + Send + Sync + 'static,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<tower::BoxError>,
+{
+    type Response = axum::http::Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        let validator = self.validator.clone();
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let response = future.await?;
+            if !is_json_content(response.headers()) {
+                return Ok(response.map(Body::new));
+            }
+
+            let (parts, body) = response.into_parts();
+            // 无法校验的响应不能放行 长度已知且超限时不需要读取
+            let too_large = body
+                .size_hint()
+                .exact()
+                .is_some_and(|len| len > VALIDATE_MAX_BODY as u64);
+            let bytes = if too_large {
+                Err("body exceeds validate limit".to_string())
+            } else {
+                axum::body::to_bytes(Body::new(body), VALIDATE_MAX_BODY)
+                    .await
+                    .map_err(|err| format!("read body error: {}", err))
+            };
+            let checked = bytes.and_then(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|err| format!("invalid json: {}", err))
+                    .and_then(|value| validator(&value))
+                    .map(|_| bytes)
+            });
+
+            match checked {
+                Ok(bytes) => Ok(axum::http::Response::from_parts(parts, Body::from(bytes))),
+                Err(reason) => {
+                    // 只在日志中记录原因 响应中不带任何细节
+                    tracing::warn!("Response rejected by validator: {}", reason);
+                    Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                }
+            }
+        })
+    }
+}
+
+/// 按媒体类型判断是否为JSON 包括application/problem+json这类+json后缀的类型
+fn is_json_content(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+#[derive(Debug)]
+pub struct ResponseValidateLayer<V> {
+    validator: Arc<V>,
+}
+
+impl<V> ResponseValidateLayer<V> {
+    pub fn new(validator: V) -> Self {
+        Self {
+            validator: Arc::new(validator),
+        }
+    }
+}
+
+impl<V> Clone for ResponseValidateLayer<V> {
+    fn clone(&self) -> Self {
+        Self {
+            validator: self.validator.clone(),
+        }
+    }
+}
+
+impl<S, V> TowerLayer<S> for ResponseValidateLayer<V> {
+    type Service = ResponseValidate<S, V>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseValidate::new(inner, self.validator.clone())
+    }
+}
+
+/// 响应中不允许出现的字段
+const FORBIDDEN_FIELDS: [&str; 2] = ["password_hash", "internal_id"];
+
+/// 递归检查JSON中是否包含敏感字段
+fn reject_sensitive_fields(value: &serde_json::Value) -> Result<(), String> {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(field) = FORBIDDEN_FIELDS.iter().find(|f| map.contains_key(**f)) {
+                return Err(format!("field {} must not be exposed", field));
+            }
+            map.values().try_for_each(reject_sensitive_fields)
+        }
+        serde_json::Value::Array(items) => items.iter().try_for_each(reject_sensitive_fields),
+        _ => Ok(()),
+    }
+}
+
+/// 要求请求携带指定的Header 可以同时要求Header的值
+/// 缺失或值不匹配时直接返回400并分别列出 不调用内部Service
+#[derive(Debug, Clone)]
+pub struct RequireHeaders<S> {
+    inner: S,
+    required: Arc<[(HeaderName, Option<HeaderValue>)]>,
+}
+
+impl<S> RequireHeaders<S> {
+    pub fn new(inner: S, required: Arc<[(HeaderName, Option<HeaderValue>)]>) -> Self {
+        Self { inner, required }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for RequireHeaders<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::response::Response<ResBody>>,
+    ResBody: From<String>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = RequireHeadersFuture<S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        // 缺失和值不匹配分开列出 方便调用方定位问题
+        let mut missing = Vec::new();
+        let mut invalid = Vec::new();
+        for (name, expected) in self.required.iter() {
+            match (req.headers().get(name), expected) {
+                (None, _) => missing.push(name.as_str()),
+                (Some(value), Some(expected)) if value != expected => invalid.push(name.as_str()),
+                _ => {}
+            }
+        }
+
+        if missing.is_empty() && invalid.is_empty() {
+            return RequireHeadersFuture::Inner {
+                response_future: self.inner.call(req),
+            };
+        }
+
+        let mut problems = Vec::new();
+        if !missing.is_empty() {
+            problems.push(format!("Missing required headers: {}", missing.join(", ")));
+        }
+        if !invalid.is_empty() {
+            problems.push(format!("Invalid header values: {}", invalid.join(", ")));
+        }
+        RequireHeadersFuture::Rejected {
+            message: Some(problems.join("; ")),
+        }
+    }
+}
+
+#[pin_project(project = RequireHeadersFutureProj)]
+#[derive(Debug)]
+pub enum RequireHeadersFuture<F> {
+    Inner {
+        #[pin]
+        response_future: F,
+    },
+    Rejected {
+        message: Option<String>,
+    },
+}
+
+impl<F, B, E> Future for RequireHeadersFuture<F>
+where
+    F: Future<Output = Result<axum::response::Response<B>, E>>,
+    B: From<String>,
+{
+    type Output = F::Output;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        match self.project() {
+            RequireHeadersFutureProj::Inner { response_future } => response_future.poll(cx),
+            RequireHeadersFutureProj::Rejected { message } => {
+                let message = message.take().expect("polled after completion");
+                let mut response = axum::response::Response::new(B::from(message));
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                Poll::Ready(Ok(response))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RequireHeadersLayer {
+    required: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl RequireHeadersLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 要求Header存在
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.required.push((name, None));
+        self
+    }
+
+    /// 要求Header存在并且值相等
+    pub fn header_value(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.required.push((name, Some(value)));
+        self
+    }
+}
+
+impl<S> TowerLayer<S> for RequireHeadersLayer {
+    type Service = RequireHeaders<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireHeaders::new(inner, self.required.clone().into())
+    }
+}
+
+/// RFC 7807 问题详情
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: Option<String>) -> Self {
+        Self {
+            kind: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or("Unknown").to_string(),
+            status: status.as_u16(),
+            detail,
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> axum::response::Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_vec(&self).unwrap_or_default();
+        (
+            status,
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// 服务端错误返回给客户端的通用描述 具体原因只写入日志
+const INTERNAL_ERROR_DETAIL: &str = "An internal error occurred";
+const UNAVAILABLE_DETAIL: &str = "The service is temporarily unavailable";
+
+/// 将内部的错误和没有响应体的错误状态转换为 application/problem+json
+/// 已经带有响应体的错误响应保持不变
+#[derive(Debug, Clone)]
+pub struct ProblemDetails<S> {
+    inner: S,
+    // 内部Service未就绪的错误 在下一次call时返回
+    ready_error: Option<String>,
+}
+
+impl<S> ProblemDetails<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            ready_error: None,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<axum::http::Request<ReqBody>> for ProblemDetails<S>
+where
+    S: Service<axum::http::Request<ReqBody>, Response = axum::http::Response<ResBody>>,
+    S::Error: fmt::Display,
+    S::Future: Send + 'static,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<tower::BoxError>,
+{
+    type Response = axum::http::Response<Body>;
+    type Error = std::convert::Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        // 内部Service不可用时在call中返回错误
+        if let Err(err) = ready!(self.inner.poll_ready(cx)) {
+            self.ready_error = Some(err.to_string());
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        if let Some(cause) = self.ready_error.take() {
+            tracing::error!("Service unavailable: {}", cause);
+            let problem = Problem::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some(UNAVAILABLE_DETAIL.to_string()),
+            );
+            return Box::pin(async move { Ok(problem.into_response()) });
+        }
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let response = match future.await {
+                Ok(response) => response,
+                Err(err) => {
+                    // 内部错误只记录日志 不把错误信息暴露给客户端
+                    tracing::error!("Internal error: {}", err);
+                    let problem = Problem::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Some(INTERNAL_ERROR_DETAIL.to_string()),
+                    );
+                    return Ok(problem.into_response());
+                }
+            };
+
+            let status = response.status();
+            let is_empty = response.body().size_hint().exact() == Some(0);
+            if (status.is_client_error() || status.is_server_error()) && is_empty {
+                // 保留原响应的Header 例如429的Retry-After 内容相关的Header以Problem为准
+                let (mut parts, _) = response.into_parts();
+                parts.headers.remove(header::CONTENT_TYPE);
+                parts.headers.remove(header::CONTENT_LENGTH);
+                let mut problem = Problem::new(status, None).into_response();
+                problem.headers_mut().extend(parts.headers);
+                return Ok(problem);
+            }
+
+            Ok(response.map(Body::new))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProblemDetailsLayer;
+
+impl<S> TowerLayer<S> for ProblemDetailsLayer {
+    type Service = ProblemDetails<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ProblemDetails::new(inner)
+    }
+}
+
+/// 默认最多保留的请求记录数
+const DEFAULT_RECORD_CAPACITY: usize = 100;
+
+/// 记录下来的请求 只保存方法、路径和Header
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedRequest {
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+}
+
+/// 有界的请求记录 超出容量时丢弃最早的记录
+#[derive(Debug, Clone)]
+pub struct RequestLog {
+    entries: Arc<Mutex<VecDeque<RecordedRequest>>>,
+    capacity: usize,
+}
+
+impl RequestLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    fn push(&self, request: RecordedRequest) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(request);
+    }
+
+    /// 按到达顺序返回当前的记录
+    pub fn snapshot(&self) -> Vec<RecordedRequest> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Default for RequestLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECORD_CAPACITY)
+    }
+}
+
+/// 记录经过的请求 用于调试时抓取流量 不影响请求的处理
+#[derive(Debug, Clone)]
+pub struct RequestRecorder<S> {
+    inner: S,
+    log: RequestLog,
+}
+
+impl<S> RequestRecorder<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_log(inner, RequestLog::default())
+    }
+
+    pub fn with_log(inner: S, log: RequestLog) -> Self {
+        Self { inner, log }
+    }
+
+    pub fn recorded(&self) -> Vec<RecordedRequest> {
+        self.log.snapshot()
+    }
+}
+
+impl<S, ReqBody> Service<axum::http::Request<ReqBody>> for RequestRecorder<S>
+where
+    S: Service<axum::http::Request<ReqBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: axum::http::Request<ReqBody>) -> Self::Future {
+        let headers = req
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        self.log.push(RecordedRequest {
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            headers,
+        });
+        self.inner.call(req)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestRecorderLayer {
+    log: RequestLog,
+}
+
+impl RequestRecorderLayer {
+    pub fn new(log: RequestLog) -> Self {
+        Self { log }
+    }
+}
+
+impl<S> TowerLayer<S> for RequestRecorderLayer {
+    type Service = RequestRecorder<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestRecorder::with_log(inner, self.log.clone())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Tracing
+    let console_layer = ConsoleLayer::new()
+        .with_span_events(FmtSpan::CLOSE)
+        .pretty()
+        .with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(console_layer).init();
+
+    let addr = "0.0.0.0:3000";
+
+    // LOG_BODY_PREVIEW控制日志中记录的响应体字节数
+    let body_preview = std::env::var("LOG_BODY_PREVIEW")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let tower_log_layer = MyLogLayer::new().body_preview(body_preview);
+    let security_headers_layer =
+        SecurityHeadersLayer::new(HeaderValue::from_static("default-src 'self'"));
+    let request_counts = RequestCounts::default();
+    let metrics_counts = request_counts.clone();
+    let app = Router::new()
+        .route("/", get(index_handler))
+        .route(
+            "/metrics",
+            get(move || async move { metrics_counts.render() }),
+        )
+        .route("/hello", get(hello_handler).layer(ETagLayer))
+        .route(
+            "/profile",
+            get(profile_handler).layer(ResponseValidateLayer::new(reject_sensitive_fields)),
+        )
+        .route("/stream", get(stream_handler))
+        .route(
+            "/tenant",
+            get(hello_handler)
+                .layer(TenantQuotaLayer::new(10, Duration::from_secs(60)).quota("free", 3)),
+        )
+        .route(
+            "/echo",
+            post(echo_handler).layer(RequireHeadersLayer::new().header(header::CONTENT_TYPE)),
+        )
+        .layer(ProblemDetailsLayer)
+        .layer(BodyLimitLayer::new(1024))
+        .layer(RequestCounterLayer::new(request_counts))
+        .layer(security_headers_layer)
+        .layer(ServerTimingLayer)
+        .layer(tower_log_layer);
+
+    // 设置了RECORD_REQUESTS时记录请求 通过/debug/requests查看
+    let app = if std::env::var("RECORD_REQUESTS").is_ok() {
+        let request_log = RequestLog::default();
+        let recorded = request_log.clone();
+        app.route(
+            "/debug/requests",
+            get(move || async move { Json(recorded.snapshot()) }),
+        )
+        .layer(RequestRecorderLayer::new(request_log))
+    } else {
+        app
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Listening on: {}", addr);
+
+    // 路由之前改写路径 /api/v1/hello和/hello访问同一个Handler
+    // Router::layer在路由匹配之后才执行 改写路径需要包在Router外层
+    let app = PathRewriteLayer::new().rule("/api/v1", "").layer(app);
+
+    axum::serve(
+        listener,
+        ServiceExt::<axum::extract::Request>::into_make_service(app),
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn index_handler() -> Result<&'static str, impl IntoResponse> {
+    Err((StatusCode::INTERNAL_SERVER_ERROR).into_response())
+}
+
+/// 内容不变的响应 配合ETag可以返回304
+async fn hello_handler() -> &'static str {
+    "Hello, ETag"
+}
+
+/// 用户信息 模拟错误地带上了内部字段
+async fn profile_handler() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "name": "manon",
+        "password_hash": "$argon2id$v=19$...",
+    }))
+}
+
+/// 流式响应
+async fn stream_handler() -> Body {
+    let chunks = ["Hello", ", ", "Stream"].map(Ok::<_, std::convert::Infallible>);
+    Body::from_stream(tokio_stream::iter(chunks))
+}
+
+/// 原样返回请求体
+async fn echo_handler(body: String) -> String {
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use std::sync::atomic::AtomicUsize;
+    use tower::ServiceExt as _;
+
+    fn tenant_request(tenant: &str) -> axum::http::Request<Body> {
+        axum::http::Request::builder()
+            .uri("/")
+            .header(TENANT_HEADER, tenant)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tenant_quota_per_tenant() {
+        // Layer被clone之后仍然可以设置配额
+        let layer = TenantQuotaLayer::new(2, Duration::from_secs(60));
+        let _ = layer.clone();
+        let layer = layer.quota("free", 1);
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(layer);
+
+        let status = |tenant: &'static str| {
+            let app = app.clone();
+            async move { app.oneshot(tenant_request(tenant)).await.unwrap().status() }
+        };
+        assert_eq!(status("free").await, StatusCode::OK);
+        let response = app.clone().oneshot(tenant_request("free")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+        // 其他租户使用默认配额 互不影响
+        assert_eq!(status("paid").await, StatusCode::OK);
+        assert_eq!(status("paid").await, StatusCode::OK);
+        assert_eq!(status("paid").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn tenant_quota_sweeps_expired_windows() {
+        let layer = TenantQuotaLayer::new(1, Duration::from_millis(20));
+        let state = layer.state;
+        for i in 0..100 {
+            state.acquire(&format!("tenant-{}", i)).unwrap();
+        }
+        assert_eq!(state.windows.len(), 100);
+
+        std::thread::sleep(Duration::from_millis(30));
+        state.acquire("tenant-new").unwrap();
+        assert_eq!(state.windows.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_counts_use_matched_route() {
+        let counts = RequestCounts::default();
+        let app = Router::new()
+            .route("/users/:id", get(|| async { "user" }))
+            .layer(RequestCounterLayer::new(counts.clone()));
+
+        for uri in ["/users/1", "/users/2", "/nope/1", "/nope/2"] {
+            let request = axum::http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let snapshot = counts.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot["/users/:id"], 2);
+        assert_eq!(snapshot[UNMATCHED_ROUTE_LABEL], 2);
+    }
+
+    #[test]
+    fn request_counts_escape_label_values() {
+        let counts = RequestCounts::default();
+        counts.increment("a\"b\\c\nd");
+        assert_eq!(
+            counts.render(),
+            "http_requests_total{path=\"a\\\"b\\\\c\\nd\"} 1\n"
+        );
+    }
+
+    fn get_request(uri: &str, if_none_match: Option<&str>) -> axum::http::Request<Body> {
+        let mut request = axum::http::Request::builder().uri(uri);
+        if let Some(tag) = if_none_match {
+            request = request.header(header::IF_NONE_MATCH, tag);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn etag_not_modified_keeps_cache_headers() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    (
+                        [
+                            (header::CACHE_CONTROL, "max-age=60"),
+                            (header::VARY, "accept"),
+                        ],
+                        "hello",
+                    )
+                }),
+            )
+            .layer(ETagLayer);
+
+        let response = app.clone().oneshot(get_request("/", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // 强校验和弱校验都匹配
+        for tag in [
+            etag.clone(),
+            format!("W/{}", etag),
+            format!("\"x\", {}", etag),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(get_request("/", Some(&tag)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", tag);
+            assert_eq!(response.headers()[header::ETAG], etag.as_str());
+            assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+            assert_eq!(response.headers()[header::VARY], "accept");
+        }
+
+        let response = app
+            .oneshot(get_request("/", Some("\"other\"")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn etag_skips_streaming_bodies() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    let chunks = futures_util::stream::iter([
+                        Ok::<_, std::io::Error>(Bytes::from("a")),
+                        Ok(Bytes::from("b")),
+                    ]);
+                    Body::from_stream(chunks)
+                }),
+            )
+            .layer(ETagLayer);
+
+        let response = app.oneshot(get_request("/", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::ETAG));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "ab");
+    }
+
+    /// 长度已知的分块Body 记录被读取了多少块
+    #[derive(Debug, Default)]
+    struct CountedChunks {
+        chunks: std::collections::VecDeque<Bytes>,
+        polled: Arc<AtomicUsize>,
+    }
+
+    impl HttpBody for CountedChunks {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Option<Result<http_body::Frame<Bytes>, Self::Error>>> {
+            let chunk = self.chunks.pop_front();
+            if chunk.is_some() {
+                self.polled.fetch_add(1, Ordering::SeqCst);
+            }
+            Poll::Ready(chunk.map(|chunk| Ok(http_body::Frame::data(chunk))))
+        }
+
+        fn size_hint(&self) -> http_body::SizeHint {
+            let len = self.chunks.iter().map(|chunk| chunk.len() as u64).sum();
+            http_body::SizeHint::with_exact(len)
+        }
+    }
+
+    #[tokio::test]
+    async fn log_preview_reads_only_prefix() {
+        let polled = Arc::new(AtomicUsize::new(0));
+        let handler_polled = polled.clone();
+        let handler = tower::service_fn(move |_req: axum::http::Request<Body>| {
+            let body = CountedChunks {
+                chunks: ["hello ", "tower ", "world"]
+                    .into_iter()
+                    .map(Bytes::from)
+                    .collect(),
+                polled: handler_polled.clone(),
+            };
+            async move {
+                let response = axum::http::Response::builder()
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(body)
+                    .unwrap();
+                Ok::<_, std::convert::Infallible>(response)
+            }
+        });
+        let service = MyLogLayer::new().body_preview(4).layer(handler);
+
+        let response = service.oneshot(get_request("/", None)).await.unwrap();
+        // 预览只需要第一块 其余数据还没有被读取
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "17");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "hello tower world");
+        assert_eq!(polled.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn problem_details_hide_internal_errors() {
+        let handler = tower::service_fn(|_req: axum::http::Request<Body>| async {
+            Err::<axum::http::Response<Body>, _>("db password=secret")
+        });
+        let service = ProblemDetailsLayer.layer(handler);
+
+        let response = service.oneshot(get_request("/", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["status"], 500);
+        assert_eq!(problem["detail"], INTERNAL_ERROR_DETAIL);
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn path_rewrite_matches_whole_segments() {
+        let rewrite = PathRewriteLayer::new()
+            .rule("/api/v1", "/v1")
+            .rule("/old/", "/new/")
+            .rule("/legacy", "")
+            .layer(());
+        assert_eq!(
+            rewrite.rewrite("/api/v1/users").as_deref(),
+            Some("/v1/users")
+        );
+        assert_eq!(rewrite.rewrite("/api/v1").as_deref(), Some("/v1"));
+        // 前缀必须在路径分隔处结束
+        assert_eq!(rewrite.rewrite("/api/v12"), None);
+        assert_eq!(rewrite.rewrite("/old/page").as_deref(), Some("/new/page"));
+        assert_eq!(rewrite.rewrite("/legacy").as_deref(), Some("/"));
+        assert_eq!(rewrite.rewrite("/other"), None);
+
+        // 在路由之前改写 查询参数保持不变
+        let app = Router::new().route("/v1/users", get(|uri: Uri| async move { uri.to_string() }));
+        let app = PathRewriteLayer::new().rule("/api/v1", "/v1").layer(app);
+        let response = app
+            .oneshot(get_request("/api/v1/users?page=2", None))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "/v1/users?page=2");
+    }
+
+    #[tokio::test]
+    async fn response_validate_rejects_with_sanitized_500() {
+        let handler = tower::service_fn(|req: axum::http::Request<Body>| async move {
+            let (content_type, body) = match req.uri().path() {
+                "/problem" => (
+                    "application/problem+json",
+                    r#"{"internal_id":7}"#.to_string(),
+                ),
+                "/large" => (
+                    "application/json",
+                    format!("[{}0]", "0,".repeat(VALIDATE_MAX_BODY)),
+                ),
+                "/text" => ("text/plain", "internal_id".to_string()),
+                _ => (
+                    "application/json; charset=utf-8",
+                    r#"{"name":"alice"}"#.to_string(),
+                ),
+            };
+            let response = axum::http::Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap();
+            Ok::<_, std::convert::Infallible>(response)
+        });
+        let service = ResponseValidateLayer::new(reject_sensitive_fields).layer(handler);
+
+        // +json类型同样校验 超限的响应不放行 响应中不带拒绝原因
+        for path in ["/problem", "/large"] {
+            let response = service
+                .clone()
+                .oneshot(get_request(path, None))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "{}",
+                path
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert!(body.is_empty(), "{}", path);
+        }
+
+        for (path, expected) in [("/text", "internal_id"), ("/user", r#"{"name":"alice"}"#)] {
+            let response = service
+                .clone()
+                .oneshot(get_request(path, None))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn require_headers_lists_missing_and_invalid() {
+        let app = Router::new().route("/", get(|| async { "ok" })).layer(
+            RequireHeadersLayer::new()
+                .header(HeaderName::from_static("x-request-id"))
+                .header(HeaderName::from_static("x-client"))
+                .header_value(
+                    HeaderName::from_static("x-api-version"),
+                    HeaderValue::from_static("2"),
+                ),
+        );
+        let request = |headers: &[(&'static str, &'static str)]| {
+            let mut builder = axum::http::Request::builder().uri("/");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(&[("x-client", "web"), ("x-api-version", "1")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "Missing required headers: x-request-id; Invalid header values: x-api-version"
+        );
+
+        // 全部满足时调用内部Service
+        let response = app
+            .oneshot(request(&[
+                ("x-request-id", "1"),
+                ("x-client", "web"),
+                ("x-api-version", "2"),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn security_headers_fill_missing_only() {
+        let app = Router::new()
+            .route("/", get(|| async { "plain" }))
+            .route(
+                "/custom",
+                get(|| async {
+                    (
+                        [(header::CONTENT_SECURITY_POLICY, "default-src 'none'")],
+                        "custom",
+                    )
+                }),
+            )
+            .layer(
+                SecurityHeadersLayer::new(HeaderValue::from_static("default-src 'self'")).header(
+                    header::X_FRAME_OPTIONS,
+                    HeaderValue::from_static("SAMEORIGIN"),
+                ),
+            );
+
+        let response = app.clone().oneshot(get_request("/", None)).await.unwrap();
+        let headers = response.headers();
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(
+            headers[header::CONTENT_SECURITY_POLICY],
+            "default-src 'self'"
+        );
+
+        // Handler自己设置的CSP优先
+        let response = app.oneshot(get_request("/custom", None)).await.unwrap();
+        let csp: Vec<_> = response
+            .headers()
+            .get_all(header::CONTENT_SECURITY_POLICY)
+            .iter()
+            .collect();
+        assert_eq!(csp, ["default-src 'none'"]);
+    }
+
+    #[tokio::test]
+    async fn body_limit_rejects_before_inner_service() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let handler = tower::service_fn(move |_req: axum::http::Request<Body>| {
+            handler_calls.fetch_add(1, Ordering::SeqCst);
+            async { Ok::<_, std::convert::Infallible>(axum::http::Response::new(Body::empty())) }
+        });
+        let service = BodyLimitLayer::new(8).layer(handler);
+        let post = |body: Body| {
+            axum::http::Request::builder()
+                .method("POST")
+                .uri("/")
+                .body(body)
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(post(Body::from("12345678")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let response = service
+            .clone()
+            .oneshot(post(Body::from("123456789")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // 流式请求没有Content-Length 也没有长度上限
+        let chunks = futures_util::stream::iter([Ok::<_, std::io::Error>(Bytes::from("1"))]);
+        let response = service
+            .oneshot(post(Body::from_stream(chunks)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::LENGTH_REQUIRED);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn server_timing_reports_app_duration() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    (
+                        [(HeaderName::from_static("server-timing"), "db;dur=1")],
+                        "ok",
+                    )
+                }),
+            )
+            .layer(ServerTimingLayer);
+
+        let response = app.oneshot(get_request("/", None)).await.unwrap();
+        let timings: Vec<_> = response
+            .headers()
+            .get_all("server-timing")
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect();
+        // Handler自己的记录保留 追加app的耗时
+        assert_eq!(timings[0], "db;dur=1");
+        let duration: f64 = timings[1]
+            .strip_prefix("app;dur=")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(duration >= 20.0, "{}", duration);
+    }
+
+    #[tokio::test]
+    async fn request_recorder_keeps_latest_in_order() {
+        let handler = tower::service_fn(|_req: axum::http::Request<Body>| async {
+            Ok::<_, std::convert::Infallible>(axum::http::Response::new(Body::empty()))
+        });
+        let mut recorder = RequestRecorder::with_log(handler, RequestLog::new(3));
+
+        for i in 0..5 {
+            let request = axum::http::Request::builder()
+                .uri(format!("/items/{}?secret=1", i))
+                .header("x-seq", i.to_string())
+                .body(Body::empty())
+                .unwrap();
+            recorder.ready().await.unwrap().call(request).await.unwrap();
+        }
+
+        // 超出容量时丢弃最早的记录 只保存路径不保存查询参数
+        let recorded = recorder.recorded();
+        let paths: Vec<_> = recorded.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/items/2", "/items/3", "/items/4"]);
+        assert_eq!(recorded[0].method, "GET");
+        assert_eq!(recorded[2].headers["x-seq"], "4");
+    }
 }
@@ -1,27 +1,77 @@
 #![allow(dead_code)]
 use anyhow::Result;
-use std::{collections::HashMap, future::Future, pin::Pin};
-use tracing::level_filters::LevelFilter;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::future::{FutureExt as _, Shared};
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, OnceCell};
+use tracing::{level_filters::LevelFilter, Instrument};
 use tracing_subscriber::{
     fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, Layer,
 };
 
-/// Tower的基础概念和由来
-/// 逐步完善这个Trait的定义和实现
+// Tower的基础概念和由来
+// 逐步完善这个Trait的定义和实现
 
 /// 模拟Request
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MockRequest {
     url: String,
+    // 请求自带的截止时间
+    deadline: Option<Instant>,
+    // 相同幂等Key的请求只会执行一次
+    idempotency_key: Option<String>,
 }
 /// 模拟Response
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MockResponse {
     url: String,
     headers: HashMap<String, String>,
     body: String,
 }
 
+/// 从Request中提取Key 用于缓存等按请求区分的场景
+trait RequestKey {
+    fn key(&self) -> String;
+}
+
+impl RequestKey for MockRequest {
+    fn key(&self) -> String {
+        self.url.clone()
+    }
+}
+
+/// 从Request中读取截止时间
+trait RequestDeadline {
+    fn deadline(&self) -> Option<Instant>;
+}
+
+impl RequestDeadline for MockRequest {
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+/// 从Request中读取幂等Key
+trait RequestIdempotencyKey {
+    fn idempotency_key(&self) -> Option<String>;
+}
+
+impl RequestIdempotencyKey for MockRequest {
+    fn idempotency_key(&self) -> Option<String> {
+        self.idempotency_key.clone()
+    }
+}
+
 #[derive(Debug)]
 struct Server;
 
@@ -33,6 +83,8 @@ impl Server {
         // Mock Request
         let request = MockRequest {
             url: "http://www.mockapi.com".to_string(),
+            deadline: None,
+            idempotency_key: None,
         };
 
         // 交给Handler
@@ -210,25 +262,1499 @@ impl<T> EvoTimeoutHandler<T> {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let console_layer = tracing_subscriber::fmt::Layer::new()
-        .with_span_events(FmtSpan::CLOSE)
-        .pretty()
-        .with_filter(LevelFilter::INFO);
+/// 分阶段超时的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TieredTimeoutError {
+    /// 调用后迟迟没有开始执行
+    Ready,
+    /// 开始执行后没有在规定时间内完成
+    Call,
+}
 
-    tracing_subscriber::registry().with(console_layer).init();
+impl std::fmt::Display for TieredTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TieredTimeoutError::Ready => f.pad("Ready Timeout"),
+            TieredTimeoutError::Call => f.pad("Call Timeout"),
+        }
+    }
+}
 
-    let server: Server = Server;
+impl std::error::Error for TieredTimeoutError {}
 
-    let say_hello_handler = EvoSayHelloHandler {
-        request_duration: std::time::Duration::from_secs(1),
+/// 分别限制就绪时间和执行时间
+/// EvoHandler没有poll_ready 从call到内部Future第一次挂起后被唤醒视为就绪阶段
+/// 例如内部在等待并发许可 拿到许可之前都算作就绪阶段
+/// 内部第一个等待点就是实际工作时 工作时间也计入就绪阶段 就绪超时需要留出余量
+/// 执行时间从call开始计算 覆盖整个调用
+#[derive(Debug, Clone)]
+struct EvoTieredTimeout<T> {
+    inner_handler: T,
+    ready_timeout: Duration,
+    call_timeout: Duration,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoTieredTimeout<T>
+where
+    Request: 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    T::Error: From<TieredTimeoutError>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+        // 从call开始计时 Future迟迟没有被poll也算在就绪时间内
+        let issued_at = tokio::time::Instant::now();
+
+        Box::pin(async move {
+            let mut inner = std::pin::pin!(this.inner_handler.call(request));
+
+            let call = async {
+                // 第一次poll就完成时直接返回 挂起后再次被poll说明已经就绪
+                let mut suspended = false;
+                let first_resolved = std::future::poll_fn(|cx| match inner.as_mut().poll(cx) {
+                    std::task::Poll::Ready(response) => std::task::Poll::Ready(Some(response)),
+                    std::task::Poll::Pending if suspended => std::task::Poll::Ready(None),
+                    std::task::Poll::Pending => {
+                        suspended = true;
+                        std::task::Poll::Pending
+                    }
+                });
+
+                // biased保证定时器先被检查 超时唤醒时不会被误认为已经就绪
+                tokio::select! {
+                    biased;
+                    _ = tokio::time::sleep_until(issued_at + this.ready_timeout) => {
+                        return Err(T::Error::from(TieredTimeoutError::Ready));
+                    }
+                    response = first_resolved => {
+                        if let Some(response) = response {
+                            return response;
+                        }
+                    }
+                }
+
+                inner.await
+            };
+
+            match tokio::time::timeout_at(issued_at + this.call_timeout, call).await {
+                Ok(response) => response,
+                Err(_) => Err(T::Error::from(TieredTimeoutError::Call)),
+            }
+        })
+    }
+}
+
+impl<T> EvoTieredTimeout<T> {
+    fn new(handler: T, ready_timeout: Duration, call_timeout: Duration) -> Self {
+        Self {
+            inner_handler: handler,
+            ready_timeout,
+            call_timeout,
+        }
+    }
+}
+
+/// 按Request自带的截止时间超时 没有截止时间时不限制
+#[derive(Debug, Clone)]
+struct EvoDeadlineHandler<T> {
+    inner_handler: T,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoDeadlineHandler<T>
+where
+    Request: RequestDeadline + 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    T::Error: From<tokio::time::error::Elapsed>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+
+        Box::pin(async move {
+            let Some(deadline) = request.deadline() else {
+                return this.inner_handler.call(request).await;
+            };
+
+            let result =
+                tokio::time::timeout_at(deadline.into(), this.inner_handler.call(request)).await;
+
+            match result {
+                Ok(response) => response,
+                Err(elapsed) => Err(T::Error::from(elapsed)),
+            }
+        })
+    }
+}
+
+impl<T> EvoDeadlineHandler<T> {
+    fn new(handler: T) -> Self {
+        Self {
+            inner_handler: handler,
+        }
+    }
+}
+
+/// 重试预算 令牌桶 每次重试消耗一个令牌 按时间匀速补充
+/// 多个Handler共享同一个预算 大范围故障时限制重试的总量 避免重试风暴
+#[derive(Debug)]
+struct RetryBudget {
+    capacity: f64,
+    // 每秒补充的令牌数
+    refill_rate: f64,
+    // 当前令牌数和上次补充的时间
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl RetryBudget {
+    fn new(capacity: usize, refill_rate: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_rate,
+            tokens: Mutex::new((capacity as f64, Instant::now())),
+        }
+    }
+
+    /// 取出一个令牌 预算耗尽时返回false
+    fn try_withdraw(&self) -> bool {
+        let mut guard = self.tokens.lock().unwrap();
+        let (tokens, refilled_at) = &mut *guard;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled_at).as_secs_f64() * self.refill_rate)
+            .min(self.capacity);
+        *refilled_at = now;
+
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// 失败时重试 每次重试都需要一份新的Request
+#[derive(Debug, Clone)]
+struct EvoRetryHandler<T> {
+    inner_handler: T,
+    max_retries: usize,
+    // 为空时不限制重试的总量
+    budget: Option<Arc<RetryBudget>>,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoRetryHandler<T>
+where
+    Request: Clone + 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+
+        Box::pin(async move {
+            let mut attempts = 0;
+            loop {
+                match this.inner_handler.call(request.clone()).await {
+                    // 预算耗尽时直接返回错误 不再重试
+                    Err(_)
+                        if attempts < this.max_retries
+                            && this
+                                .budget
+                                .as_ref()
+                                .is_none_or(|budget| budget.try_withdraw()) =>
+                    {
+                        attempts += 1
+                    }
+                    result => return result,
+                }
+            }
+        })
+    }
+}
+
+impl<T> EvoRetryHandler<T> {
+    fn new(handler: T, max_retries: usize) -> Self {
+        Self {
+            inner_handler: handler,
+            max_retries,
+            budget: None,
+        }
+    }
+
+    /// 使用共享的重试预算
+    fn with_budget(mut self, budget: Arc<RetryBudget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+}
+
+/// 总是失败的Handler 记录被调用的次数
+#[derive(Debug, Clone, Default)]
+struct EvoAlwaysFailHandler {
+    calls: Arc<AtomicUsize>,
+}
+
+impl<Request> EvoHandler<Request> for EvoAlwaysFailHandler {
+    type Error = anyhow::Error;
+    type Response = MockResponse;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, _request: Request) -> Self::Future {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async { Err(anyhow::anyhow!("backend unavailable")) })
+    }
+}
+
+/// 组合Handler 从外到内依次书写 最后一个是基础Handler
+/// handler_stack![timeout(500ms), retry(3), base]
+/// 等价于 EvoTimeoutHandler::new(EvoRetryHandler::new(base, 3), 500ms)
+/// 时长支持ms和s两种后缀 格式错误时编译失败
+macro_rules! handler_stack {
+    [timeout($duration:tt), $($rest:tt)+] => {
+        EvoTimeoutHandler::new(handler_stack![$($rest)+], {
+            const DURATION: Duration = stack_duration(stringify!($duration));
+            DURATION
+        })
     };
+    [retry($max_retries:expr), $($rest:tt)+] => {
+        EvoRetryHandler::new(handler_stack![$($rest)+], $max_retries)
+    };
+    [$base:expr] => {
+        $base
+    };
+}
+
+/// 解析handler_stack!中的时长 例如500ms、2s 在常量中调用 格式错误时编译失败
+const fn stack_duration(literal: &str) -> Duration {
+    let bytes = literal.as_bytes();
+    let mut value: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'_') {
+        if bytes[i] != b'_' {
+            value = value * 10 + (bytes[i] - b'0') as u64;
+        }
+        i += 1;
+    }
+    if i == 0 {
+        panic!("timeout must start with a number, e.g. 500ms or 2s");
+    }
+    match bytes.len() - i {
+        2 if bytes[i] == b'm' && bytes[i + 1] == b's' => Duration::from_millis(value),
+        1 if bytes[i] == b's' => Duration::from_secs(value),
+        _ => panic!("timeout must end with ms or s"),
+    }
+}
 
-    let timeout_handler =
-        EvoTimeoutHandler::new(say_hello_handler, std::time::Duration::from_millis(500));
+/// 负载均衡 在多个等价的Handler之间轮询
+#[derive(Debug, Clone)]
+struct EvoBalance<T> {
+    handlers: Vec<T>,
+    // Clone之后依旧共享同一个下标 保证轮询顺序
+    next: Arc<AtomicUsize>,
+}
 
-    server.run(timeout_handler).await?;
+impl<Request, T> EvoHandler<Request> for EvoBalance<T>
+where
+    T: EvoHandler<Request> + Clone,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = T::Future;
 
-    Ok(())
+    fn call(&mut self, request: Request) -> Self::Future {
+        // 取出当前下标并递增 超出长度后从头开始
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.handlers.len();
+        self.handlers[index].call(request)
+    }
+}
+
+impl<T> EvoBalance<T> {
+    fn new(handlers: Vec<T>) -> Self {
+        assert!(
+            !handlers.is_empty(),
+            "EvoBalance requires at least one handler"
+        );
+        Self {
+            handlers,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+/// 一致性哈希 相同Key的请求总是路由到同一个Handler 便于利用各自的本地缓存
+/// 每个Handler在环上放置多个虚拟节点 让Key分布得更均匀
+#[derive(Debug, Clone)]
+struct EvoConsistentHash<T> {
+    handlers: Vec<T>,
+    // 按哈希值排序的虚拟节点 保存对应的Handler下标
+    ring: Arc<Vec<(u64, usize)>>,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoConsistentHash<T>
+where
+    Request: RequestKey,
+    T: EvoHandler<Request> + Clone,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = T::Future;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let index = self.route(&request.key());
+        self.handlers[index].call(request)
+    }
+}
+
+impl<T> EvoConsistentHash<T> {
+    fn new(handlers: Vec<T>, virtual_nodes: usize) -> Self {
+        assert!(
+            !handlers.is_empty(),
+            "EvoConsistentHash requires at least one handler"
+        );
+        assert!(virtual_nodes > 0, "virtual_nodes must be positive");
+
+        let mut ring: Vec<(u64, usize)> = (0..handlers.len())
+            .flat_map(|index| (0..virtual_nodes).map(move |node| (hash_of(&(index, node)), index)))
+            .collect();
+        ring.sort_unstable();
+
+        Self {
+            handlers,
+            ring: Arc::new(ring),
+        }
+    }
+
+    /// 顺时针找到第一个不小于Key哈希值的虚拟节点 超过末尾时回到环的起点
+    fn route(&self, key: &str) -> usize {
+        let hash = hash_of(&key);
+        let position = self.ring.partition_point(|(node, _)| *node < hash);
+        self.ring[position % self.ring.len()].1
+    }
+}
+
+/// 固定种子的哈希 同一个值在不同进程中的结果一致
+fn hash_of(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 限制同时执行的内部调用数量
+#[derive(Debug, Clone)]
+struct EvoConcurrencyLimit<T> {
+    inner_handler: T,
+    semaphore: Arc<tokio::sync::Semaphore>,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoConcurrencyLimit<T>
+where
+    Request: 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+
+        Box::pin(async move {
+            // 许可随Future一起释放 拿不到许可时排队等待
+            let _permit = this
+                .semaphore
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            this.inner_handler.call(request).await
+        })
+    }
+}
+
+impl<T> EvoConcurrencyLimit<T> {
+    fn new(handler: T, max: usize) -> Self {
+        Self {
+            inner_handler: handler,
+            semaphore: Arc::new(tokio::sync::Semaphore::new(max)),
+        }
+    }
+}
+
+/// 缓存内部Handler的成功响应 TTL内相同Key的请求直接返回缓存
+/// 缓存条目数达到上限后不再缓存新的响应 直到过期的条目被清理
+#[derive(Debug, Clone)]
+struct EvoCache<T, R> {
+    inner_handler: T,
+    ttl: Duration,
+    capacity: usize,
+    cache: Arc<DashMap<String, (Instant, R)>>,
+    // 上次清理过期条目的时间
+    last_sweep: Arc<Mutex<Instant>>,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoCache<T, T::Response>
+where
+    Request: RequestKey + 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    T::Response: Clone + 'static,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+        let key = request.key();
+
+        Box::pin(async move {
+            // 命中且未过期时不再调用内部Handler
+            if let Some(entry) = this.cache.get(&key) {
+                let (cached_at, response) = entry.value();
+                if cached_at.elapsed() < this.ttl {
+                    return Ok(response.clone());
+                }
+            }
+
+            // 只缓存成功的响应
+            let response = this.inner_handler.call(request).await?;
+            this.sweep();
+            if this.cache.len() < this.capacity || this.cache.contains_key(&key) {
+                this.cache.insert(key, (Instant::now(), response.clone()));
+            }
+            Ok(response)
+        })
+    }
+}
+
+impl<T, R> EvoCache<T, R> {
+    fn new(handler: T, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            inner_handler: handler,
+            ttl,
+            capacity,
+            cache: Arc::new(DashMap::new()),
+            last_sweep: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// 每个TTL周期清理一次过期的条目 不再被访问的Key不会一直占用内存
+    fn sweep(&self) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if last_sweep.elapsed() < self.ttl {
+                return;
+            }
+            *last_sweep = Instant::now();
+        }
+        self.cache
+            .retain(|_, (cached_at, _)| cached_at.elapsed() < self.ttl);
+    }
+}
+
+/// 幂等Key对应的响应 第一次调用完成前为空
+type IdempotentSlot<R> = (Instant, Arc<OnceCell<R>>);
+
+/// 相同幂等Key的请求在TTL内只调用一次内部Handler 之后直接返回第一次的响应
+/// 同时到达的重复请求会等待第一次调用完成 失败的调用不会被记录 可以重试
+/// 没有幂等Key的请求直接交给内部Handler
+#[derive(Debug, Clone)]
+struct EvoIdempotent<T, R> {
+    inner_handler: T,
+    ttl: Duration,
+    // TTL从第一次请求到达时开始计算
+    responses: Arc<DashMap<String, IdempotentSlot<R>>>,
+    // 上次清理过期响应的时间
+    last_sweep: Arc<Mutex<Instant>>,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoIdempotent<T, T::Response>
+where
+    Request: RequestIdempotencyKey + 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    T::Response: Clone + 'static,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+        let key = request.idempotency_key();
+
+        Box::pin(async move {
+            let Some(key) = key else {
+                return this.inner_handler.call(request).await;
+            };
+
+            this.sweep();
+            let cell = {
+                let mut entry = this
+                    .responses
+                    .entry(key)
+                    .or_insert_with(|| (Instant::now(), Arc::new(OnceCell::new())));
+                if entry.0.elapsed() >= this.ttl {
+                    *entry = (Instant::now(), Arc::new(OnceCell::new()));
+                }
+                entry.1.clone()
+            };
+
+            let response = cell
+                .get_or_try_init(|| this.inner_handler.call(request))
+                .await?;
+            Ok(response.clone())
+        })
+    }
+}
+
+impl<T, R> EvoIdempotent<T, R> {
+    fn new(handler: T, ttl: Duration) -> Self {
+        Self {
+            inner_handler: handler,
+            ttl,
+            responses: Arc::new(DashMap::new()),
+            last_sweep: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// 每个TTL周期清理一次过期的响应 幂等Key大多不会重复 不清理会一直占用内存
+    /// 等待中的请求持有OnceCell 被清理后仍然可以拿到响应
+    fn sweep(&self) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if last_sweep.elapsed() < self.ttl {
+                return;
+            }
+            *last_sweep = Instant::now();
+        }
+        self.responses
+            .retain(|_, (created_at, _)| created_at.elapsed() < self.ttl);
+    }
+}
+
+/// 进行中的调用 完成后由发起者发送响应 失败或被取消时不会发送
+type InFlight<R> = Shared<oneshot::Receiver<R>>;
+
+/// 合并进行中的相同请求 同一时刻相同Key的请求只调用一次内部Handler 共享它的响应
+/// 和EvoCache不同 调用完成后立即移除 之后到达的请求会重新调用
+/// 错误无法共享 发起的调用失败时 等待的请求各自调用内部Handler
+#[derive(Debug, Clone)]
+struct EvoSingleFlight<T, R> {
+    inner_handler: T,
+    in_flight: Arc<DashMap<String, InFlight<R>>>,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoSingleFlight<T, T::Response>
+where
+    Request: RequestKey + 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    T::Response: Clone + 'static,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+        let key = request.key();
+
+        Box::pin(async move {
+            // 已有相同Key的调用时等待它的结果 否则由自己发起
+            let sender = match this.in_flight.entry(key.clone()) {
+                Entry::Occupied(entry) => {
+                    let in_flight = entry.get().clone();
+                    drop(entry);
+                    if let Ok(response) = in_flight.clone().await {
+                        return Ok(response);
+                    }
+                    // 发起者失败或被取消 移除这次调用后自己重新调用
+                    this.in_flight
+                        .remove_if(&key, |_, current| current.ptr_eq(&in_flight));
+                    return this.inner_handler.call(request).await;
+                }
+                Entry::Vacant(entry) => {
+                    let (sender, receiver) = oneshot::channel();
+                    entry.insert(receiver.shared());
+                    sender
+                }
+            };
+
+            let result = this.inner_handler.call(request).await;
+            this.in_flight.remove(&key);
+            if let Ok(response) = &result {
+                let _ = sender.send(response.clone());
+            }
+            result
+        })
+    }
+}
+
+impl<T, R> EvoSingleFlight<T, R> {
+    fn new(handler: T) -> Self {
+        Self {
+            inner_handler: handler,
+            in_flight: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+/// 按条件拦截Request 不满足时直接返回reject生成的错误 不调用内部Handler
+#[derive(Debug, Clone)]
+struct EvoGuard<T, P, F> {
+    inner_handler: T,
+    predicate: P,
+    reject: F,
+}
+
+impl<Request, T, P, F> EvoHandler<Request> for EvoGuard<T, P, F>
+where
+    Request: 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    P: Fn(&Request) -> bool,
+    F: Fn(&Request) -> T::Error,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if !(self.predicate)(&request) {
+            let err = (self.reject)(&request);
+            return Box::pin(async move { Err(err) });
+        }
+
+        let mut this = self.inner_handler.clone();
+        Box::pin(async move { this.call(request).await })
+    }
+}
+
+impl<T, P, F> EvoGuard<T, P, F> {
+    fn new(handler: T, predicate: P, reject: F) -> Self {
+        Self {
+            inner_handler: handler,
+            predicate,
+            reject,
+        }
+    }
+}
+
+/// 记录每次调用的到达、结果和耗时 内部Handler无需关心tracing
+#[derive(Debug, Clone)]
+struct EvoInstrument<T> {
+    inner_handler: T,
+    name: &'static str,
+}
+
+impl<Request, T> EvoHandler<Request> for EvoInstrument<T>
+where
+    Request: 'static,
+    T: EvoHandler<Request> + Clone + 'static,
+    T::Error: std::fmt::Display,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut this = self.clone();
+        let span = tracing::info_span!("handler", name = this.name);
+
+        Box::pin(
+            async move {
+                tracing::info!("request received");
+                let start = Instant::now();
+
+                let result = this.inner_handler.call(request).await;
+                let latency_ms = start.elapsed().as_millis() as u64;
+
+                match &result {
+                    Ok(_) => tracing::info!(latency_ms, "request ok"),
+                    Err(err) => tracing::error!(latency_ms, error = %err, "request failed"),
+                }
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+impl<T> EvoInstrument<T> {
+    fn new(handler: T, name: &'static str) -> Self {
+        Self {
+            inner_handler: handler,
+            name,
+        }
+    }
+}
+
+/// 一次处理多个Request的Handler 返回的Response和Request按顺序一一对应
+trait BatchHandler<Request> {
+    type Response;
+    type Error;
+
+    type Future: Future<Output = Result<Vec<Self::Response>, Self::Error>>;
+    fn call_batch(&mut self, requests: Vec<Request>) -> Self::Future;
+}
+
+/// 批量调用失败的原因
+#[derive(Debug, Clone, thiserror::Error)]
+enum BatchError<E> {
+    #[error("batch handler error: {0}")]
+    Inner(E),
+    #[error("batch handler returned {got} responses for {expected} requests")]
+    Mismatch { expected: usize, got: usize },
+    #[error("batch was dropped before completion")]
+    Canceled,
+}
+
+/// 等待批量结果的调用方
+type BatchSlot<Response, Error> = oneshot::Sender<Result<Response, BatchError<Error>>>;
+/// 等待发出的一批Request
+type PendingBatch<Request, Response, Error> = Vec<(Request, BatchSlot<Response, Error>)>;
+/// 同一个EvoBatch的所有Clone共享的待发出批次
+type SharedBatch<Request, Response, Error> = Arc<Mutex<BatchQueue<Request, Response, Error>>>;
+
+/// 当前正在收集的批次 每取走一批代号加一
+struct BatchQueue<Request, Response, Error> {
+    generation: u64,
+    items: PendingBatch<Request, Response, Error>,
+}
+
+impl<Request, Response, Error> BatchQueue<Request, Response, Error> {
+    fn take(&mut self) -> PendingBatch<Request, Response, Error> {
+        self.generation += 1;
+        std::mem::take(&mut self.items)
+    }
+}
+
+/// 把窗口内到达的Request合并成一批 只调用一次内部Handler
+/// 每批的第一个Request启动一个后台定时任务 窗口结束后发出 凑满max_size时立即在后台发出
+/// 定时任务只发出自己负责的那一批 调用方被取消不影响同一批的其他调用方
+struct EvoBatch<T, Request>
+where
+    T: BatchHandler<Request>,
+{
+    inner_handler: T,
+    window: Duration,
+    max_size: usize,
+    pending: SharedBatch<Request, T::Response, T::Error>,
+}
+
+impl<T, Request> Clone for EvoBatch<T, Request>
+where
+    T: BatchHandler<Request> + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner_handler: self.inner_handler.clone(),
+            window: self.window,
+            max_size: self.max_size,
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<Request, T> EvoHandler<Request> for EvoBatch<T, Request>
+where
+    Request: Send + 'static,
+    T: BatchHandler<Request> + Clone + Send + 'static,
+    T::Future: Send,
+    T::Response: Send + 'static,
+    T::Error: Clone + Send + 'static,
+{
+    type Response = T::Response;
+    type Error = BatchError<T::Error>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let this = self.clone();
+
+        Box::pin(async move {
+            let (tx, rx) = oneshot::channel();
+            let (full, leader) = {
+                let mut pending = this.pending.lock().unwrap();
+                pending.items.push((request, tx));
+                if pending.items.len() >= this.max_size {
+                    (Some(pending.take()), None)
+                } else if pending.items.len() == 1 {
+                    (None, Some(pending.generation))
+                } else {
+                    (None, None)
+                }
+            };
+
+            if let Some(batch) = full {
+                tokio::spawn(Self::flush(this.inner_handler.clone(), batch));
+            } else if let Some(generation) = leader {
+                tokio::spawn(this.clone().flush_after_window(generation));
+            }
+
+            rx.await.unwrap_or(Err(BatchError::Canceled))
+        })
+    }
+}
+
+impl<T, Request> EvoBatch<T, Request>
+where
+    T: BatchHandler<Request>,
+    T::Error: Clone,
+{
+    fn new(handler: T, window: Duration, max_size: usize) -> Self {
+        Self {
+            inner_handler: handler,
+            window,
+            max_size: max_size.max(1),
+            pending: Arc::new(Mutex::new(BatchQueue {
+                generation: 0,
+                items: Vec::new(),
+            })),
+        }
+    }
+
+    /// 窗口结束后发出指定代号的批次 这一批已经被凑满发出时什么也不做
+    async fn flush_after_window(self, generation: u64) {
+        tokio::time::sleep(self.window).await;
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.generation != generation {
+                return;
+            }
+            pending.take()
+        };
+        Self::flush(self.inner_handler, batch).await;
+    }
+
+    /// 调用内部Handler并把结果分发给每个调用方
+    async fn flush(mut handler: T, batch: PendingBatch<Request, T::Response, T::Error>) {
+        let (requests, slots): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        let expected = requests.len();
+
+        match handler.call_batch(requests).await {
+            Ok(responses) if responses.len() == expected => {
+                for (slot, response) in slots.into_iter().zip(responses) {
+                    let _ = slot.send(Ok(response));
+                }
+            }
+            Ok(responses) => {
+                let got = responses.len();
+                for slot in slots {
+                    let _ = slot.send(Err(BatchError::Mismatch { expected, got }));
+                }
+            }
+            Err(err) => {
+                for slot in slots {
+                    let _ = slot.send(Err(BatchError::Inner(err.clone())));
+                }
+            }
+        }
+    }
+}
+
+/// 批量的SayHello 记录被调用的次数
+#[derive(Debug, Clone, Default)]
+struct EvoBatchSayHelloHandler {
+    calls: Arc<AtomicUsize>,
+}
+
+impl BatchHandler<MockRequest> for EvoBatchSayHelloHandler {
+    type Response = MockResponse;
+    type Error = String;
+    type Future = Pin<Box<dyn Future<Output = Result<Vec<Self::Response>, Self::Error>> + Send>>;
+
+    fn call_batch(&mut self, requests: Vec<MockRequest>) -> Self::Future {
+        let this = self.clone();
+
+        Box::pin(async move {
+            this.calls.fetch_add(1, Ordering::SeqCst);
+            let size = requests.len();
+            Ok(requests
+                .into_iter()
+                .map(|request| MockResponse {
+                    url: request.url,
+                    headers: HashMap::new(),
+                    body: format!("Batch Hello World! ({} in batch)", size),
+                })
+                .collect())
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let console_layer = tracing_subscriber::fmt::Layer::new()
+        .with_span_events(FmtSpan::CLOSE)
+        .pretty()
+        .with_filter(LevelFilter::INFO);
+
+    tracing_subscriber::registry().with(console_layer).init();
+
+    let server: Server = Server;
+
+    let say_hello_handler = EvoSayHelloHandler {
+        request_duration: std::time::Duration::from_secs(1),
+    };
+
+    let handler = handler_stack![timeout(500ms), retry(3), say_hello_handler];
+    let handler = EvoInstrument::new(handler, "say_hello");
+
+    server.run(handler).await?;
+
+    // 同时到达的请求合并为一次调用
+    let batch_handler = EvoBatchSayHelloHandler::default();
+    let calls = batch_handler.calls.clone();
+    let handler = EvoBatch::new(batch_handler, Duration::from_millis(50), 10);
+    let requests = (0..3).map(|i| {
+        let mut handler = handler.clone();
+        handler.call(MockRequest {
+            url: format!("http://www.mockapi.com/{}", i),
+            deadline: None,
+            idempotency_key: None,
+        })
+    });
+    for response in futures_util::future::join_all(requests).await {
+        println!("Batch Response: {:?}", response);
+    }
+    println!(
+        "Batch handler called {} times",
+        calls.load(Ordering::SeqCst)
+    );
+
+    // 相同幂等Key的第二次请求不会再调用内部Handler
+    let say_hello_handler = EvoSayHelloHandler {
+        request_duration: Duration::from_millis(200),
+    };
+    let mut handler = EvoIdempotent::new(
+        EvoInstrument::new(say_hello_handler, "idempotent_inner"),
+        Duration::from_secs(60),
+    );
+    for _ in 0..2 {
+        let start = Instant::now();
+        let response = handler
+            .call(MockRequest {
+                url: "http://www.mockapi.com/orders".to_string(),
+                deadline: None,
+                idempotency_key: Some("order-1".to_string()),
+            })
+            .await;
+        println!(
+            "Idempotent Response: {:?} in {:?}",
+            response,
+            start.elapsed()
+        );
+    }
+
+    // 同时到达的相同请求只调用一次内部Handler
+    let say_hello_handler = EvoSayHelloHandler {
+        request_duration: Duration::from_millis(200),
+    };
+    let handler =
+        EvoSingleFlight::new(EvoInstrument::new(say_hello_handler, "single_flight_inner"));
+    let start = Instant::now();
+    let requests = (0..3).map(|_| {
+        let mut handler = handler.clone();
+        handler.call(MockRequest {
+            url: "http://www.mockapi.com/hot".to_string(),
+            deadline: None,
+            idempotency_key: None,
+        })
+    });
+    for response in futures_util::future::join_all(requests).await {
+        println!(
+            "Single Flight Response: {:?} in {:?}",
+            response,
+            start.elapsed()
+        );
+    }
+
+    // 预算耗尽后失败的请求不再重试 补充之后恢复重试
+    let fail_handler = EvoAlwaysFailHandler::default();
+    let calls = fail_handler.calls.clone();
+    let budget = Arc::new(RetryBudget::new(5, 10.0));
+    let mut handler = EvoRetryHandler::new(fail_handler, 3).with_budget(budget);
+    let request = MockRequest {
+        url: "http://www.mockapi.com/down".to_string(),
+        deadline: None,
+        idempotency_key: None,
+    };
+    for _ in 0..4 {
+        let before = calls.load(Ordering::SeqCst);
+        let _ = handler.call(request.clone()).await;
+        println!(
+            "Retry budget: request called inner {} times",
+            calls.load(Ordering::SeqCst) - before
+        );
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let before = calls.load(Ordering::SeqCst);
+    let _ = handler.call(request).await;
+    println!(
+        "Retry budget after refill: request called inner {} times",
+        calls.load(Ordering::SeqCst) - before
+    );
+
+    // 相同的Key总是落到同一个Handler 不同的Key分散到各个Handler
+    let handlers = (0..3)
+        .map(|_| EvoSayHelloHandler::default())
+        .collect::<Vec<_>>();
+    let mut handler = EvoConsistentHash::new(handlers, 100);
+    let mut spread = [0; 3];
+    for i in 0..300 {
+        let url = format!("http://www.mockapi.com/users/{}", i);
+        let index = handler.route(&url);
+        assert_eq!(index, handler.route(&url));
+        spread[index] += 1;
+    }
+    println!("Consistent hash spread: {:?}", spread);
+    let response = handler
+        .call(MockRequest {
+            url: "http://www.mockapi.com/users/1".to_string(),
+            deadline: None,
+            idempotency_key: None,
+        })
+        .await;
+    println!("Consistent Hash Response: {:?}", response);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_request(url: &str) -> MockRequest {
+        MockRequest {
+            url: url.to_string(),
+            deadline: None,
+            idempotency_key: None,
+        }
+    }
+
+    fn say_hello(millis: u64) -> EvoSayHelloHandler {
+        EvoSayHelloHandler {
+            request_duration: Duration::from_millis(millis),
+        }
+    }
+
+    /// 记录调用次数 原样返回请求的url
+    #[derive(Debug, Clone, Default)]
+    struct CountingHandler {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl EvoHandler<MockRequest> for CountingHandler {
+        type Error = anyhow::Error;
+        type Response = MockResponse;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+        fn call(&mut self, request: MockRequest) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                Ok(MockResponse {
+                    url: request.url,
+                    headers: HashMap::new(),
+                    body: String::new(),
+                })
+            })
+        }
+    }
+
+    fn keyed_request(url: &str, key: &str) -> MockRequest {
+        MockRequest {
+            idempotency_key: Some(key.to_string()),
+            ..mock_request(url)
+        }
+    }
+
+    fn tiered_error(result: Result<MockResponse, anyhow::Error>) -> TieredTimeoutError {
+        *result
+            .unwrap_err()
+            .downcast_ref::<TieredTimeoutError>()
+            .expect("tiered timeout error")
+    }
+
+    #[tokio::test]
+    async fn tiered_timeout_ready_tier() {
+        // 许可被占用时一直拿不到许可 在就绪阶段超时
+        let mut limit = EvoConcurrencyLimit::new(say_hello(300), 1);
+        let mut handler = EvoTieredTimeout::new(
+            limit.clone(),
+            Duration::from_millis(50),
+            Duration::from_secs(1),
+        );
+
+        let (holder, waiter) = tokio::join!(
+            limit.call(mock_request("/holder")),
+            handler.call(mock_request("/waiter"))
+        );
+        assert!(holder.is_ok());
+        assert_eq!(tiered_error(waiter), TieredTimeoutError::Ready);
+    }
+
+    #[tokio::test]
+    async fn tiered_timeout_call_tier() {
+        // 很快就绪但执行时间过长 在执行阶段超时
+        let mut handler = EvoTieredTimeout::new(
+            say_hello(300),
+            Duration::from_secs(1),
+            Duration::from_millis(50),
+        );
+        let result = handler.call(mock_request("/slow")).await;
+        assert_eq!(tiered_error(result), TieredTimeoutError::Call);
+
+        let mut handler = EvoTieredTimeout::new(
+            say_hello(10),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        );
+        assert!(handler.call(mock_request("/fast")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn idempotent_replays_and_sweeps_expired_keys() {
+        let inner = CountingHandler::default();
+        let mut handler = EvoIdempotent::new(inner.clone(), Duration::from_millis(50));
+
+        // 相同的Key只调用一次 返回第一次的响应
+        let first = handler.call(keyed_request("/a", "order-1")).await.unwrap();
+        let replay = handler.call(keyed_request("/b", "order-1")).await.unwrap();
+        assert_eq!(first.url, "/a");
+        assert_eq!(replay.url, "/a");
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+
+        for i in 0..10 {
+            let key = format!("unique-{}", i);
+            handler.call(keyed_request("/c", &key)).await.unwrap();
+        }
+        assert_eq!(handler.responses.len(), 11);
+
+        // 过期后下一次调用会清理所有过期的Key
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let response = handler.call(keyed_request("/d", "order-1")).await.unwrap();
+        assert_eq!(response.url, "/d");
+        assert_eq!(handler.responses.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_is_bounded_and_swept() {
+        let inner = CountingHandler::default();
+        let mut handler = EvoCache::new(inner.clone(), Duration::from_millis(50), 3);
+
+        // 命中缓存时不调用内部Handler
+        handler.call(mock_request("/a")).await.unwrap();
+        handler.call(mock_request("/a")).await.unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+
+        // 超过容量的响应不会被缓存
+        for i in 0..5 {
+            handler
+                .call(mock_request(&format!("/b{}", i)))
+                .await
+                .unwrap();
+        }
+        assert_eq!(handler.cache.len(), 3);
+
+        // 过期的条目被清理后可以缓存新的响应
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        handler.call(mock_request("/c")).await.unwrap();
+        assert_eq!(handler.cache.len(), 1);
+        handler.call(mock_request("/c")).await.unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn stack_duration_suffixes() {
+        assert_eq!(stack_duration("500ms"), Duration::from_millis(500));
+        assert_eq!(stack_duration("2s"), Duration::from_secs(2));
+        assert_eq!(stack_duration("1_000ms"), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn handler_stack_timeout_literal() {
+        // 内层先重试 每次都超时 外层的2s不会触发
+        let mut handler = handler_stack![timeout(2s), retry(1), timeout(50ms), say_hello(300)];
+        let start = Instant::now();
+        let result = handler.call(mock_request("/stack")).await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let mut handler = handler_stack![timeout(500ms), retry(3), say_hello(10)];
+        assert!(handler.call(mock_request("/stack")).await.is_ok());
+    }
+
+    fn batch_request(i: usize) -> MockRequest {
+        mock_request(&format!("http://www.mockapi.com/{}", i))
+    }
+
+    #[tokio::test]
+    async fn batch_survives_cancelled_leader() {
+        let batch_handler = EvoBatchSayHelloHandler::default();
+        let calls = batch_handler.calls.clone();
+        let handler = EvoBatch::new(batch_handler, Duration::from_millis(50), 10);
+
+        // 第一个调用方加入批次后被取消
+        let leader = handler.clone().call(batch_request(0));
+        let _ = tokio::time::timeout(Duration::from_millis(1), leader).await;
+
+        // 后台定时任务仍然会发出这一批
+        let response = handler.clone().call(batch_request(1)).await.unwrap();
+        assert_eq!(response.body, "Batch Hello World! (2 in batch)");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn batch_timer_does_not_flush_next_batch_early() {
+        let batch_handler = EvoBatchSayHelloHandler::default();
+        let calls = batch_handler.calls.clone();
+        let handler = EvoBatch::new(batch_handler, Duration::from_millis(100), 2);
+
+        // 凑满立即发出 不等待窗口
+        let start = Instant::now();
+        let full = (0..2).map(|i| handler.clone().call(batch_request(i)));
+        for response in futures_util::future::join_all(full).await {
+            assert!(response.unwrap().body.contains("2 in batch"));
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 上一批的定时任务在下一批的窗口内到期 不能提前发出下一批
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let next_start = Instant::now();
+        let mut next = handler.clone().call(batch_request(2));
+        let early = tokio::time::timeout(Duration::from_millis(80), &mut next).await;
+        assert!(early.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let response = next.await.unwrap();
+        assert_eq!(response.body, "Batch Hello World! (1 in batch)");
+        assert!(next_start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn balance_round_robins_across_clones() {
+        let backends: Vec<CountingHandler> = (0..3).map(|_| CountingHandler::default()).collect();
+        let balance = EvoBalance::new(backends.clone());
+
+        // 每个Clone共享同一个下标 按顺序轮流调用
+        for round in 1..=2 {
+            for (index, backend) in backends.iter().enumerate() {
+                let mut handler = balance.clone();
+                handler.call(mock_request("/")).await.unwrap();
+                assert_eq!(backend.calls.load(Ordering::SeqCst), round, "{}", index);
+            }
+        }
+    }
+
+    /// 记录调用次数和同时执行的调用数的峰值
+    #[derive(Debug, Clone, Default)]
+    struct ConcurrencyProbe {
+        calls: Arc<AtomicUsize>,
+        active: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    impl EvoHandler<MockRequest> for ConcurrencyProbe {
+        type Error = anyhow::Error;
+        type Response = MockResponse;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+        fn call(&mut self, request: MockRequest) -> Self::Future {
+            let this = self.clone();
+            Box::pin(async move {
+                this.calls.fetch_add(1, Ordering::SeqCst);
+                let active = this.active.fetch_add(1, Ordering::SeqCst) + 1;
+                this.peak.fetch_max(active, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                this.active.fetch_sub(1, Ordering::SeqCst);
+                Ok(MockResponse {
+                    url: request.url,
+                    headers: HashMap::new(),
+                    body: String::new(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_caps_in_flight_calls() {
+        let probe = ConcurrencyProbe::default();
+        let limit = EvoConcurrencyLimit::new(probe.clone(), 2);
+
+        let calls = (0..6).map(|i| limit.clone().call(mock_request(&format!("/{}", i))));
+        for result in futures_util::future::join_all(calls).await {
+            assert!(result.is_ok());
+        }
+        assert_eq!(probe.peak.load(Ordering::SeqCst), 2);
+        assert_eq!(probe.active.load(Ordering::SeqCst), 0);
+    }
+
+    fn deadline_request(after: Duration) -> MockRequest {
+        MockRequest {
+            deadline: Some(Instant::now() + after),
+            ..mock_request("/deadline")
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline_uses_request_deadline() {
+        let mut handler = EvoDeadlineHandler::new(say_hello(100));
+
+        let result = handler
+            .call(deadline_request(Duration::from_millis(20)))
+            .await;
+        assert!(result.unwrap_err().is::<tokio::time::error::Elapsed>());
+        assert!(handler
+            .call(deadline_request(Duration::from_secs(1)))
+            .await
+            .is_ok());
+        // 没有截止时间时不限制
+        assert!(handler.call(mock_request("/none")).await.is_ok());
+    }
+
+    /// 把日志写入内存 用于检查输出
+    #[derive(Debug, Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn instrument_logs_outcome_and_latency() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut ok = EvoInstrument::new(say_hello(0), "say_hello");
+        let response = ok.call(mock_request("/ok")).await.unwrap();
+        assert_eq!(response.body, "Evo Hello World!");
+
+        let mut failing = EvoInstrument::new(EvoAlwaysFailHandler::default(), "always_fail");
+        assert!(failing.call(mock_request("/fail")).await.is_err());
+
+        let text = logs.text();
+        assert!(text.contains("handler{name=\"say_hello\"}"), "{}", text);
+        assert!(text.contains("request ok"), "{}", text);
+        assert!(text.contains("request failed"), "{}", text);
+        assert!(text.contains("error=backend unavailable"), "{}", text);
+        assert!(text.contains("latency_ms="), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn guard_rejects_without_calling_inner() {
+        let inner = CountingHandler::default();
+        let mut handler = EvoGuard::new(
+            inner.clone(),
+            |request: &MockRequest| request.url.starts_with("/public"),
+            |request: &MockRequest| anyhow::anyhow!("forbidden: {}", request.url),
+        );
+
+        let err = handler.call(mock_request("/admin")).await.unwrap_err();
+        assert_eq!(err.to_string(), "forbidden: /admin");
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 0);
+
+        let response = handler.call(mock_request("/public/a")).await.unwrap();
+        assert_eq!(response.url, "/public/a");
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_routes_keys_stably() {
+        let backends: Vec<CountingHandler> = (0..3).map(|_| CountingHandler::default()).collect();
+        let mut handler = EvoConsistentHash::new(backends.clone(), 64);
+
+        // 相同Key总是路由到同一个Handler
+        for _ in 0..3 {
+            handler.call(mock_request("/user/42")).await.unwrap();
+        }
+        let hits: Vec<usize> = backends
+            .iter()
+            .map(|backend| backend.calls.load(Ordering::SeqCst))
+            .collect();
+        assert_eq!(hits.iter().filter(|&&calls| calls == 3).count(), 1);
+        assert_eq!(hits.iter().sum::<usize>(), 3);
+
+        // 每个Handler都分到Key 新增Handler时其他Key保持原来的路由
+        let keys: Vec<String> = (0..300).map(|i| format!("/user/{}", i)).collect();
+        let before: Vec<usize> = keys.iter().map(|key| handler.route(key)).collect();
+        for index in 0..3 {
+            assert!(before.contains(&index), "handler {} got no keys", index);
+        }
+        let grown = EvoConsistentHash::new(vec![CountingHandler::default(); 4], 64);
+        for (key, old) in keys.iter().zip(before) {
+            let new = grown.route(key);
+            assert!(
+                new == old || new == 3,
+                "{} moved from {} to {}",
+                key,
+                old,
+                new
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_budget_is_shared_and_refills() {
+        // 两个Handler共享2个令牌 不补充
+        let budget = Arc::new(RetryBudget::new(2, 0.0));
+        let first = EvoAlwaysFailHandler::default();
+        let second = EvoAlwaysFailHandler::default();
+        let mut first_retry = EvoRetryHandler::new(first.clone(), 3).with_budget(budget.clone());
+        let mut second_retry = EvoRetryHandler::new(second.clone(), 3).with_budget(budget);
+
+        assert!(first_retry.call(mock_request("/a")).await.is_err());
+        assert_eq!(first.calls.load(Ordering::SeqCst), 3);
+        // 预算耗尽 只调用一次 不再重试
+        assert!(second_retry.call(mock_request("/b")).await.is_err());
+        assert_eq!(second.calls.load(Ordering::SeqCst), 1);
+
+        let budget = RetryBudget::new(1, 20.0);
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(budget.try_withdraw());
+    }
+
+    #[tokio::test]
+    async fn single_flight_coalesces_concurrent_calls() {
+        let probe = ConcurrencyProbe::default();
+        let handler = EvoSingleFlight::new(probe.clone());
+
+        // 同时到达的相同Key只调用一次 不同Key各自调用
+        let calls = ["/a", "/a", "/a", "/b"].map(|url| handler.clone().call(mock_request(url)));
+        let responses = futures_util::future::join_all(calls).await;
+        let urls: Vec<String> = responses
+            .into_iter()
+            .map(|response| response.unwrap().url)
+            .collect();
+        assert_eq!(urls, ["/a", "/a", "/a", "/b"]);
+        assert_eq!(probe.calls.load(Ordering::SeqCst), 2);
+
+        // 完成后不缓存 之后的请求重新调用
+        handler.clone().call(mock_request("/a")).await.unwrap();
+        assert_eq!(probe.calls.load(Ordering::SeqCst), 3);
+    }
 }
@@ -1,15 +1,22 @@
 use core::fmt;
 use std::{
     future::Future,
+    hash::Hash,
     pin::Pin,
-    task::{Context, Poll},
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::{future::Shared, FutureExt as _};
 use pin_project::pin_project;
 use tokio::time::Sleep;
-use tower::{BoxError, Service};
+use tower::{BoxError, Service, ServiceExt as _};
 
 /// 创建一个Timeout Service
 #[derive(Debug, Clone)]
@@ -113,6 +120,461 @@ impl fmt::Display for TimeoutError {
 /// 实现std::error:Error =  Display+Debug
 impl std::error::Error for TimeoutError {}
 
+/// 只在Predicate返回true时重试的Service
+/// 用于区分临时错误(超时)和永久错误(参数校验失败)
+#[derive(Debug, Clone)]
+struct RetryIf<S, P> {
+    inner: S,
+    predicate: P,
+    max_retries: usize,
+}
+impl<S, P> RetryIf<S, P> {
+    pub fn new(inner: S, max_retries: usize, predicate: P) -> Self {
+        Self {
+            inner,
+            predicate,
+            max_retries,
+        }
+    }
+}
+
+impl<S, P, Request> Service<Request> for RetryIf<S, P>
+where
+    // 每次重试都需要一份新的Request和Service
+    Request: Clone + 'static,
+    S: Service<Request> + Clone + 'static,
+    P: Fn(&S::Error) -> bool + Clone + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let this = self.clone();
+        // 已经Ready的Service交给第一次调用 后续重试使用Clone出来的Service
+        let mut inner = std::mem::replace(&mut self.inner, this.inner.clone());
+
+        Box::pin(async move {
+            let mut attempts = 0;
+            loop {
+                let result = inner.call(req.clone()).await;
+                match result {
+                    Err(err) if attempts < this.max_retries && (this.predicate)(&err) => {
+                        attempts += 1;
+                        println!("Retry attempt {}", attempts);
+                        inner = this.inner.clone();
+                        inner.ready().await?;
+                    }
+                    result => return result,
+                }
+            }
+        })
+    }
+}
+
+/// 按概率注入延迟或错误 用于测试重试、超时等下游的容错逻辑
+/// 通过CHAOS_LATENCY_PROB和CHAOS_ERROR_PROB环境变量开启
+#[derive(Debug, Clone)]
+struct FaultInjection<S> {
+    inner: S,
+    latency_prob: f64,
+    error_prob: f64,
+    latency: Duration,
+}
+impl<S> FaultInjection<S> {
+    pub fn new(inner: S, latency_prob: f64, error_prob: f64) -> Self {
+        Self {
+            inner,
+            latency_prob,
+            error_prob,
+            latency: Duration::from_millis(500),
+        }
+    }
+
+    /// 从环境变量读取概率 未设置时不注入任何故障
+    pub fn from_env(inner: S) -> Self {
+        let prob = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0.0)
+        };
+        Self::new(inner, prob("CHAOS_LATENCY_PROB"), prob("CHAOS_ERROR_PROB"))
+    }
+
+    /// 注入的延迟时长
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+}
+
+impl<S, Request> Service<Request> for FaultInjection<S>
+where
+    S: Service<Request>,
+    S::Error: Into<BoxError>,
+    S::Future: 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(|err| err.into())
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // 注入错误时不调用内部Service
+        if rand::random::<f64>() < self.error_prob {
+            return Box::pin(async { Err(Box::new(InjectedError(())) as BoxError) });
+        }
+
+        let delay = (rand::random::<f64>() < self.latency_prob).then_some(self.latency);
+        let response_future = self.inner.call(req);
+
+        Box::pin(async move {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            response_future.await.map_err(|err| err.into())
+        })
+    }
+}
+
+/// 注入的错误
+#[derive(Debug, Default)]
+pub struct InjectedError(());
+impl fmt::Display for InjectedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Injected Fault")
+    }
+}
+impl std::error::Error for InjectedError {}
+
+/// 把每个响应补齐到最短耗时 避免通过响应时间推测内部逻辑(如鉴权是否命中)
+/// 成功和失败都会补齐 超过最短耗时的响应不受影响
+#[derive(Debug, Clone)]
+struct ConstantTime<S> {
+    inner: S,
+    min_duration: Duration,
+}
+impl<S> ConstantTime<S> {
+    pub fn new(inner: S, min_duration: Duration) -> Self {
+        Self {
+            inner,
+            min_duration,
+        }
+    }
+}
+
+impl<S, Request> Service<Request> for ConstantTime<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ConstantTimeFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // 从调用时开始计时 内部耗时也计算在最短耗时内
+        ConstantTimeFuture {
+            response_future: self.inner.call(req),
+            sleep: tokio::time::sleep(self.min_duration),
+            result: None,
+        }
+    }
+}
+
+/// 先等待内部响应 再等待剩余的时间
+#[pin_project]
+pub struct ConstantTimeFuture<F: Future> {
+    #[pin]
+    response_future: F,
+    #[pin]
+    sleep: Sleep,
+    // 内部已经完成的结果 等待补齐时暂存
+    result: Option<F::Output>,
+}
+
+impl<F: Future> Future for ConstantTimeFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        if this.result.is_none() {
+            match this.response_future.poll(cx) {
+                Poll::Ready(result) => *this.result = Some(result),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        // 最短耗时未到 继续等待
+        ready!(this.sleep.as_mut().poll(cx));
+        Poll::Ready(this.result.take().expect("polled after completion"))
+    }
+}
+
+/// 相同Key的请求依次执行 不同Key的请求互不影响
+/// 和合并请求不同 每个请求都会真正调用内部Service 只是不会重叠
+struct KeyedMutex<S, K, F> {
+    inner: S,
+    key_fn: F,
+    locks: Arc<DashMap<K, Arc<tokio::sync::Mutex<()>>>>,
+}
+impl<S, K, F> KeyedMutex<S, K, F>
+where
+    K: Eq + Hash,
+{
+    pub fn new(inner: S, key_fn: F) -> Self {
+        Self {
+            inner,
+            key_fn,
+            locks: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+// Clone时共享同一组锁 否则不同的Service实例之间无法互斥
+impl<S: Clone, K, F: Clone> Clone for KeyedMutex<S, K, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key_fn: self.key_fn.clone(),
+            locks: self.locks.clone(),
+        }
+    }
+}
+
+impl<S, K, F, Request> Service<Request> for KeyedMutex<S, K, F>
+where
+    Request: 'static,
+    S: Service<Request> + Clone + 'static,
+    K: Eq + Hash + Clone + 'static,
+    F: Fn(&Request) -> K,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let key = (self.key_fn)(&req);
+        let locks = self.locks.clone();
+        // 拿到锁之后才调用内部Service 已经Ready的Service交给这次调用
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let lock = locks.entry(key.clone()).or_default().clone();
+            let result = {
+                let _guard = lock.lock().await;
+                inner.call(req).await
+            };
+
+            // 没有其他请求在等待时移除这个Key 避免Map无限增长
+            drop(lock);
+            locks.remove_if(&key, |_, lock| Arc::strong_count(lock) == 1);
+            result
+        })
+    }
+}
+
+/// 短时间缓存内部Service返回的错误 TTL内相同Key的请求直接返回缓存的错误
+/// 避免反复查询不存在的数据时每次都打到后端
+struct NegativeCache<S, K, E, F> {
+    inner: S,
+    key_fn: F,
+    ttl: Duration,
+    errors: Arc<DashMap<K, (Instant, E)>>,
+}
+impl<S, K, E, F> NegativeCache<S, K, E, F>
+where
+    K: Eq + Hash,
+{
+    pub fn new(inner: S, ttl: Duration, key_fn: F) -> Self {
+        Self {
+            inner,
+            key_fn,
+            ttl,
+            errors: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+// Clone时共享同一份缓存
+impl<S: Clone, K, E, F: Clone> Clone for NegativeCache<S, K, E, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key_fn: self.key_fn.clone(),
+            ttl: self.ttl,
+            errors: self.errors.clone(),
+        }
+    }
+}
+
+impl<S, K, E, F, Request> Service<Request> for NegativeCache<S, K, E, F>
+where
+    Request: 'static,
+    S: Service<Request, Error = E> + Clone + 'static,
+    K: Eq + Hash + 'static,
+    E: Clone + 'static,
+    F: Fn(&Request) -> K,
+{
+    type Response = S::Response;
+    type Error = E;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let key = (self.key_fn)(&req);
+
+        // 命中且未过期时不调用内部Service 过期的错误直接移除
+        if let Some(entry) = self.errors.get(&key) {
+            let (cached_at, err) = entry.value();
+            if cached_at.elapsed() < self.ttl {
+                let err = err.clone();
+                return Box::pin(async move { Err(err) });
+            }
+        }
+        let ttl = self.ttl;
+        self.errors
+            .remove_if(&key, |_, (cached_at, _)| cached_at.elapsed() >= ttl);
+
+        let errors = self.errors.clone();
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let result = inner.call(req).await;
+            // 只缓存错误 成功的响应不做处理
+            if let Err(err) = &result {
+                errors.insert(key, (Instant::now(), err.clone()));
+            }
+            result
+        })
+    }
+}
+
+type LocalBoxFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+/// 一轮防抖中还没有执行的请求
+struct Burst<Request, R, E> {
+    // 最后一个请求到达后再等待一个窗口
+    deadline: Instant,
+    // 只保留最后一个请求
+    request: Option<Request>,
+    // 这一轮所有调用共享的结果 任意调用方都可以推进它
+    run: Shared<LocalBoxFuture<Result<R, E>>>,
+}
+
+/// 防抖 相同Key的请求在窗口内连续到达时只执行最后一个
+/// 窗口内没有新请求后才调用内部Service 结果返回给这一轮的所有调用方
+/// 适合边输入边搜索这类只关心最终输入的场景
+struct Debounce<S, K, F, Request, R, E> {
+    inner: S,
+    key_fn: F,
+    window: Duration,
+    bursts: Arc<DashMap<K, Burst<Request, R, E>>>,
+}
+impl<S, K, F, Request, R, E> Debounce<S, K, F, Request, R, E>
+where
+    K: Eq + Hash,
+{
+    pub fn new(inner: S, window: Duration, key_fn: F) -> Self {
+        Self {
+            inner,
+            key_fn,
+            window,
+            bursts: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+// Clone时共享同一组待执行的请求
+impl<S: Clone, K, F: Clone, Request, R, E> Clone for Debounce<S, K, F, Request, R, E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key_fn: self.key_fn.clone(),
+            window: self.window,
+            bursts: self.bursts.clone(),
+        }
+    }
+}
+
+impl<S, K, F, Request> Service<Request> for Debounce<S, K, F, Request, S::Response, S::Error>
+where
+    Request: 'static,
+    S: Service<Request> + Clone + 'static,
+    S::Response: Clone,
+    S::Error: Clone,
+    K: Eq + Hash + Clone + 'static,
+    F: Fn(&Request) -> K,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Shared<LocalBoxFuture<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let key = (self.key_fn)(&req);
+        let deadline = Instant::now() + self.window;
+
+        match self.bursts.entry(key.clone()) {
+            // 同一轮的请求 替换掉之前的请求并推迟执行
+            Entry::Occupied(mut entry) => {
+                let burst = entry.get_mut();
+                burst.deadline = deadline;
+                burst.request = Some(req);
+                burst.run.clone()
+            }
+            Entry::Vacant(entry) => {
+                let bursts = self.bursts.clone();
+                let clone = self.inner.clone();
+                let mut inner = std::mem::replace(&mut self.inner, clone);
+
+                // 执行前才移除这一轮 移除之后到达的请求开始新的一轮
+                let run: LocalBoxFuture<_> = Box::pin(async move {
+                    let request = loop {
+                        let deadline = bursts.get(&key).expect("burst removed").deadline;
+                        if Instant::now() >= deadline {
+                            let (_, mut burst) = bursts.remove(&key).expect("burst removed");
+                            break burst.request.take().expect("burst without request");
+                        }
+                        tokio::time::sleep_until(deadline.into()).await;
+                    };
+                    inner.call(request).await
+                });
+                let run = run.shared();
+                entry.insert(Burst {
+                    deadline,
+                    request: Some(req),
+                    run: run.clone(),
+                });
+                run
+            }
+        }
+    }
+}
+
 /// 创建一个RootService作为Timeout的逻辑
 struct RootService {
     is_timeout: bool,
@@ -171,5 +633,354 @@ async fn main() -> Result<()> {
         Err(e) => println!("Err:{}", e),
     }
 
+    // 第一次调用超时 之后正常返回
+    let calls = Arc::new(AtomicUsize::new(0));
+    let flaky_service = tower::service_fn(move |_req: ()| {
+        let count = calls.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if count == 0 {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+            Ok::<_, BoxError>("Hello World".to_string())
+        }
+    });
+
+    // 只对超时错误重试
+    let mut retry_service = RetryIf::new(
+        Timeout::new(flaky_service, Duration::from_secs(1)),
+        2,
+        |err: &BoxError| err.is::<TimeoutError>(),
+    );
+
+    let result = retry_service.call(()).await;
+
+    match result {
+        Ok(data) => println!("Response:{}", data),
+        Err(e) => println!("Err:{}", e),
+    }
+
+    // 按环境变量注入故障 观察重试和超时的表现
+    let chaos_service = FaultInjection::from_env(tower::service_fn(|_req: ()| async {
+        Ok::<_, BoxError>("Hello World".to_string())
+    }))
+    .latency(Duration::from_millis(500));
+    let mut retry_service = RetryIf::new(
+        Timeout::new(chaos_service, Duration::from_millis(300)),
+        2,
+        |err: &BoxError| err.is::<TimeoutError>() || err.is::<InjectedError>(),
+    );
+
+    let result = retry_service.call(()).await;
+
+    match result {
+        Ok(data) => println!("Response:{}", data),
+        Err(e) => println!("Err:{}", e),
+    }
+
+    // 快速返回的Service也会被补齐到最短耗时
+    let mut constant_time_service = ConstantTime::new(
+        tower::service_fn(|_req: ()| async { Ok::<_, BoxError>("Hello World".to_string()) }),
+        Duration::from_millis(200),
+    );
+
+    let start = Instant::now();
+    let result = constant_time_service.call(()).await;
+
+    match result {
+        Ok(data) => println!("Response:{} in {:?}", data, start.elapsed()),
+        Err(e) => println!("Err:{}", e),
+    }
+
+    // 相同Key的请求依次执行 不同Key的请求并发执行
+    let slow_service = tower::service_fn(|req: (&'static str, usize)| async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        Ok::<_, BoxError>(format!("{}-{}", req.0, req.1))
+    });
+    let keyed_service = KeyedMutex::new(slow_service, |req: &(&'static str, usize)| req.0);
+
+    let start = Instant::now();
+    let calls = [("a", 1), ("a", 2), ("b", 1)].into_iter().map(|req| {
+        let future = keyed_service.clone().oneshot(req);
+        async move { (future.await, start.elapsed()) }
+    });
+    let results = futures_util::future::join_all(calls).await;
+
+    for (result, elapsed) in results {
+        match result {
+            Ok(data) => println!("Response:{} after {:?}", data, elapsed),
+            Err(e) => println!("Err:{}", e),
+        }
+    }
+
+    // 第二次查询相同的不存在的id 直接返回缓存的错误 不再调用内部Service
+    let calls = Arc::new(AtomicUsize::new(0));
+    let lookup_calls = calls.clone();
+    let lookup_service = tower::service_fn(move |id: &'static str| {
+        lookup_calls.fetch_add(1, Ordering::SeqCst);
+        async move { Err::<String, _>(format!("{} not found", id)) }
+    });
+    let negative_cache = NegativeCache::new(
+        lookup_service,
+        Duration::from_secs(5),
+        |id: &&'static str| *id,
+    );
+
+    for _ in 0..2 {
+        match negative_cache.clone().oneshot("missing").await {
+            Ok(data) => println!("Response:{}", data),
+            Err(e) => println!("Err:{}", e),
+        }
+    }
+    println!("Lookup called {} times", calls.load(Ordering::SeqCst));
+
+    // 同一个用户连续输入 只按最后的输入搜索一次 所有调用都拿到这次的结果
+    let calls = Arc::new(AtomicUsize::new(0));
+    let search_calls = calls.clone();
+    let search_service = tower::service_fn(move |req: (&'static str, &'static str)| {
+        search_calls.fetch_add(1, Ordering::SeqCst);
+        async move { Ok::<_, String>(format!("results for {}", req.1)) }
+    });
+    let debounce = Debounce::new(
+        search_service,
+        Duration::from_millis(100),
+        |req: &(&'static str, &'static str)| req.0,
+    );
+
+    let start = Instant::now();
+    let typing = ["r", "ru", "rus", "rust"]
+        .into_iter()
+        .enumerate()
+        .map(|(i, query)| {
+            let debounce = debounce.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(30 * i as u64)).await;
+                let result = debounce.oneshot(("alice", query)).await;
+                (query, result, start.elapsed())
+            }
+        });
+    for (query, result, elapsed) in futures_util::future::join_all(typing).await {
+        match result {
+            Ok(data) => println!("Typed {} -> {} after {:?}", query, data, elapsed),
+            Err(e) => println!("Err:{}", e),
+        }
+    }
+    println!("Search called {} times", calls.load(Ordering::SeqCst));
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn negative_cache_caches_errors_briefly() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookup_calls = calls.clone();
+        let lookup = tower::service_fn(move |key: &'static str| {
+            lookup_calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match key {
+                    "missing" => Err(format!("{} not found", key)),
+                    _ => Ok(key.to_string()),
+                }
+            }
+        });
+        let cache =
+            NegativeCache::new(lookup, Duration::from_millis(50), |key: &&'static str| *key);
+
+        // TTL内相同Key的错误直接返回 不调用内部Service
+        for _ in 0..3 {
+            let err = cache.clone().oneshot("missing").await.unwrap_err();
+            assert_eq!(err, "missing not found");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 成功的响应不缓存
+        cache.clone().oneshot("found").await.unwrap();
+        cache.clone().oneshot("found").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // 过期后重新调用
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(cache.clone().oneshot("missing").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn debounce_runs_only_the_last_request() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let search_calls = calls.clone();
+        let search = tower::service_fn(move |req: (&'static str, &'static str)| {
+            search_calls.fetch_add(1, Ordering::SeqCst);
+            async move { Ok::<_, String>(format!("results for {}", req.1)) }
+        });
+        let debounce = Debounce::new(
+            search,
+            Duration::from_millis(50),
+            |req: &(&'static str, &'static str)| req.0,
+        );
+
+        // 窗口内连续到达的请求共享最后一个请求的结果 不同Key互不影响
+        let typing = [
+            ("alice", "r", 0),
+            ("alice", "ru", 20),
+            ("bob", "go", 20),
+            ("alice", "rust", 40),
+        ]
+        .map(|(user, query, delay)| {
+            let debounce = debounce.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                debounce.oneshot((user, query)).await.unwrap()
+            }
+        });
+        let results = futures_util::future::join_all(typing).await;
+        assert_eq!(
+            results,
+            [
+                "results for rust",
+                "results for rust",
+                "results for go",
+                "results for rust"
+            ]
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // 执行之后到达的请求开始新的一轮
+        let result = debounce.clone().oneshot(("alice", "rusty")).await.unwrap();
+        assert_eq!(result, "results for rusty");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    /// 前几次失败 之后成功的Service 记录调用次数
+    fn flaky(
+        calls: Arc<AtomicUsize>,
+        failures: usize,
+    ) -> impl Service<(), Response = &'static str, Error = String> + Clone {
+        tower::service_fn(move |_: ()| {
+            let attempt = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt < failures {
+                    Err(format!("timeout #{}", attempt))
+                } else {
+                    Ok("done")
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn retry_if_skips_errors_rejected_by_predicate() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let retry = RetryIf::new(flaky(calls.clone(), 1), 3, |_: &String| false);
+
+        let err = retry.oneshot(()).await.unwrap_err();
+        assert_eq!(err, "timeout #0");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_if_retries_until_success() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let retry = RetryIf::new(flaky(calls.clone(), 2), 3, |err: &String| {
+            err.starts_with("timeout")
+        });
+
+        assert_eq!(retry.oneshot(()).await.unwrap(), "done");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // 超过最大重试次数后返回最后一次的错误
+        let calls = Arc::new(AtomicUsize::new(0));
+        let retry = RetryIf::new(flaky(calls.clone(), 5), 2, |_: &String| true);
+        assert_eq!(retry.oneshot(()).await.unwrap_err(), "timeout #2");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn fault_injection_fails_every_call_without_reaching_inner() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut service = FaultInjection::new(flaky(calls.clone(), 0), 0.0, 1.0);
+
+        for _ in 0..10 {
+            let err = service.ready().await.unwrap().call(()).await.unwrap_err();
+            assert!(err.is::<InjectedError>());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // 概率为0时直接透传
+        let service = FaultInjection::new(flaky(calls.clone(), 0), 0.0, 0.0);
+        assert_eq!(service.oneshot(()).await.unwrap(), "done");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn constant_time_pads_fast_responses() {
+        let min_duration = Duration::from_millis(200);
+        let fast = tower::service_fn(|ok: bool| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if ok {
+                Ok("granted")
+            } else {
+                Err("denied")
+            }
+        });
+        let service = ConstantTime::new(fast, min_duration);
+
+        // 成功和失败都补齐到最短耗时
+        for ok in [true, false] {
+            let start = tokio::time::Instant::now();
+            let _ = service.clone().oneshot(ok).await;
+            assert!(start.elapsed() >= min_duration);
+        }
+
+        // 超过最短耗时的响应不额外等待
+        let slow = tower::service_fn(|_: ()| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok::<_, String>(())
+        });
+        let start = tokio::time::Instant::now();
+        ConstantTime::new(slow, min_duration)
+            .oneshot(())
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn keyed_mutex_serializes_same_key_only() {
+        // 按Key记录同时在执行的请求数和峰值
+        let active = Arc::new(DashMap::<&'static str, (usize, usize)>::new());
+        let probe = active.clone();
+        let service = tower::service_fn(move |key: &'static str| {
+            let probe = probe.clone();
+            async move {
+                probe.entry(key).or_default().0 += 1;
+                {
+                    let mut entry = probe.get_mut(key).unwrap();
+                    entry.1 = entry.1.max(entry.0);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                probe.get_mut(key).unwrap().0 -= 1;
+                Ok::<_, String>(key)
+            }
+        });
+        let mutex = KeyedMutex::new(service, |key: &&'static str| *key);
+
+        let start = Instant::now();
+        let calls = ["a", "a", "a", "b", "c"].map(|key| mutex.clone().oneshot(key));
+        let results = futures_util::future::join_all(calls).await;
+        assert!(results.iter().all(Result::is_ok));
+
+        // 相同Key不重叠 不同Key并发执行
+        for key in ["a", "b", "c"] {
+            assert_eq!(active.get(key).unwrap().1, 1);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(60));
+        assert!(elapsed < Duration::from_millis(100));
+
+        // 全部完成后锁被移除
+        assert!(mutex.locks.is_empty());
+    }
+}
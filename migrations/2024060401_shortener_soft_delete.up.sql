@@ -0,0 +1 @@
+ALTER TABLE shortener ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ NULL;
@@ -0,0 +1,6 @@
+-- 新增列时已有的行会取到DEFAULT的值 再补一次防止之前手动加过可为空的列
+ALTER TABLE shortener ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ DEFAULT now();
+UPDATE shortener SET created_at = now() WHERE created_at IS NULL;
+ALTER TABLE shortener ALTER COLUMN created_at SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS shortener_created_at_idx ON shortener (created_at DESC);
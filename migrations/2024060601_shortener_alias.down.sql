@@ -0,0 +1,8 @@
+-- CHAR(6)放不下自定义别名和更长的短链接 回滚前需要先删除或重命名这些行 否则直接报错 不会截断id
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM shortener WHERE length(id) > 6) THEN
+        RAISE EXCEPTION 'shortener has ids longer than 6 characters, delete or rename them before reverting';
+    END IF;
+END $$;
+ALTER TABLE shortener ALTER COLUMN id TYPE CHAR(6);
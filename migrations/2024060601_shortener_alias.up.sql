@@ -0,0 +1,2 @@
+-- 自定义别名和更长的短链接需要更宽的id列 CHAR会补空格 改为VARCHAR
+ALTER TABLE shortener ALTER COLUMN id TYPE VARCHAR(32);
@@ -0,0 +1,2 @@
+-- 已有的短链接都是308永久跳转
+ALTER TABLE shortener ADD COLUMN IF NOT EXISTS permanent BOOLEAN NOT NULL DEFAULT TRUE;
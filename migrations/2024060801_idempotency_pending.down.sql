@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idempotency_keys_created_at_idx;
+DELETE FROM idempotency_keys WHERE url IS NULL;
+ALTER TABLE idempotency_keys ALTER COLUMN url SET NOT NULL;
@@ -0,0 +1,4 @@
+-- url为空表示首次请求还在处理中 先占用Key再创建短链接
+ALTER TABLE idempotency_keys ALTER COLUMN url DROP NOT NULL;
+-- 定期按created_at清理过期的Key
+CREATE INDEX IF NOT EXISTS idempotency_keys_created_at_idx ON idempotency_keys (created_at);
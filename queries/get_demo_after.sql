@@ -0,0 +1 @@
+SELECT * FROM migrate_demo WHERE id > $1 ORDER BY id LIMIT $2;
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT * FROM migrate_demo WHERE id = $1;
@@ -0,0 +1,4 @@
+//! examples之间共享的代码
+
+pub mod migrate;
+pub mod runtime;
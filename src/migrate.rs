@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    PgPool,
+};
+
+/// 演练模式的环境变量 设置后只列出待执行的迁移 不修改数据库
+const DRY_RUN_ENV: &str = "MIGRATE_DRY_RUN";
+
+/// 一个待执行或已执行的迁移
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationInfo {
+    pub version: i64,
+    pub description: String,
+}
+
+/// 执行迁移并记录每个执行的迁移 演练模式下只报告待执行的迁移
+#[derive(Debug)]
+pub struct MigrationRunner<'a> {
+    migrator: &'a Migrator,
+    dry_run: bool,
+}
+
+impl<'a> MigrationRunner<'a> {
+    pub fn new(migrator: &'a Migrator) -> Self {
+        Self {
+            migrator,
+            dry_run: false,
+        }
+    }
+
+    /// 设置了MIGRATE_DRY_RUN时开启演练模式
+    pub fn from_env(migrator: &'a Migrator) -> Self {
+        Self::new(migrator).dry_run(std::env::var(DRY_RUN_ENV).is_ok())
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// 返回本次执行的迁移 演练模式下返回待执行的迁移
+    pub async fn run(&self, pool: &PgPool) -> Result<Vec<MigrationInfo>> {
+        let pending = self.pending(pool).await?;
+
+        if self.dry_run {
+            for migration in &pending {
+                tracing::info!(
+                    version = migration.version,
+                    "Pending migration: {}",
+                    migration.description
+                );
+            }
+            tracing::info!("Dry run, {} migrations pending", pending.len());
+            return Ok(pending);
+        }
+
+        // 迁移按版本顺序执行 全部成功后再逐个记录
+        self.migrator.run(pool).await?;
+        for migration in &pending {
+            tracing::info!(
+                version = migration.version,
+                "Applied migration: {}",
+                migration.description
+            );
+        }
+        tracing::info!("{} migrations applied", pending.len());
+        Ok(pending)
+    }
+
+    /// 还未执行的迁移 只读取数据库 迁移记录表不存在时视为全部未执行
+    async fn pending(&self, pool: &PgPool) -> Result<Vec<MigrationInfo>> {
+        let table: Option<String> =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations')::text;")
+                .fetch_one(pool)
+                .await?;
+
+        let applied: HashSet<i64> = match table {
+            Some(_) => {
+                let mut conn = pool.acquire().await?;
+                conn.list_applied_migrations()
+                    .await?
+                    .into_iter()
+                    .map(|migration| migration.version)
+                    .collect()
+            }
+            None => HashSet::new(),
+        };
+
+        Ok(self
+            .migrator
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .filter(|migration| !applied.contains(&migration.version))
+            .map(|migration| MigrationInfo {
+                version: migration.version,
+                description: migration.description.to_string(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+    async fn migrations_table(pool: &PgPool) -> Option<String> {
+        sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations')::text;")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn dry_run_reports_pending_without_migrating(pool: PgPool) {
+        let all: Vec<i64> = MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .map(|migration| migration.version)
+            .collect();
+
+        // 新数据库上所有迁移都待执行 演练不创建迁移记录表
+        let pending = MigrationRunner::new(&MIGRATOR)
+            .dry_run(true)
+            .run(&pool)
+            .await
+            .unwrap();
+        let versions: Vec<i64> = pending.iter().map(|migration| migration.version).collect();
+        assert_eq!(versions, all);
+        assert_eq!(migrations_table(&pool).await, None);
+
+        // 执行后返回同样的迁移 再次演练时没有待执行的迁移
+        let applied = MigrationRunner::new(&MIGRATOR).run(&pool).await.unwrap();
+        assert_eq!(applied, pending);
+        assert!(migrations_table(&pool).await.is_some());
+        let pending = MigrationRunner::new(&MIGRATOR)
+            .dry_run(true)
+            .run(&pool)
+            .await
+            .unwrap();
+        assert!(pending.is_empty());
+    }
+}
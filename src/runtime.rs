@@ -0,0 +1,47 @@
+use anyhow::Result;
+use tokio::runtime::Runtime;
+
+/// 工作线程数的环境变量
+const WORKER_THREADS_ENV: &str = "WORKER_THREADS";
+
+/// 根据WORKER_THREADS构建Runtime 未设置时使用CPU核数
+pub fn build_runtime() -> Result<Runtime> {
+    let worker_threads = worker_threads(std::env::var(WORKER_THREADS_ENV).ok().as_deref())?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_io()
+        .enable_time()
+        .build()?;
+    tracing::info!("Worker threads: {}", worker_threads);
+
+    Ok(runtime)
+}
+
+/// 解析工作线程数 必须是正整数 未设置时使用CPU核数
+fn worker_threads(value: Option<&str>) -> Result<usize> {
+    match value {
+        Some(value) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| anyhow::anyhow!("invalid {}: {}", WORKER_THREADS_ENV, value)),
+        None => Ok(std::thread::available_parallelism().map_or(1, |n| n.get())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_threads_must_be_positive() {
+        assert_eq!(worker_threads(Some("4")).unwrap(), 4);
+        assert!(worker_threads(None).unwrap() > 0);
+
+        for value in ["0", "-1", "four", ""] {
+            let err = worker_threads(Some(value)).unwrap_err().to_string();
+            assert_eq!(err, format!("invalid WORKER_THREADS: {}", value));
+        }
+    }
+}